@@ -0,0 +1,97 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::command::Decision;
+use visonic::ResProcessStatus;
+
+#[derive(Clone, Deserialize)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+}
+
+/// One received command, written as a single JSON line.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub command_id: Option<String>,
+    pub panel: String,
+    pub source: String,
+    pub payload: String,
+    pub decision: Decision,
+    pub reason: Option<String>,
+    pub process_token: Option<String>,
+    pub process_status: Option<ResProcessStatus>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Default)]
+pub struct AuditFilter {
+    pub panel: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+/// Append-only JSON lines audit log shared by every command source.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl AuditConfig {
+    pub fn open(&self) -> AuditLog {
+        AuditLog {
+            path: self.path.clone(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn append(&self, record: &AuditRecord) -> std::io::Result<()> {
+        let line = serde_json::to_string(record)?;
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)
+    }
+
+    /// Records matching `filter` in log order, `limit` keeps the most recent ones.
+    pub fn query(&self, filter: &AuditFilter) -> std::io::Result<Vec<AuditRecord>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut records: Vec<AuditRecord> = vec![];
+
+        for line in reader.lines() {
+            let line = line?;
+            let record = match serde_json::from_str::<AuditRecord>(&line) {
+                Ok(record) => record,
+                Err(err) => {
+                    warn!("Skipping malformed audit log line: {}", err);
+                    continue;
+                }
+            };
+            let panel_match = filter.panel.as_ref().map_or(true, |p| p.eq(&record.panel));
+            let since_match = filter.since.map_or(true, |since| record.timestamp >= since);
+            if panel_match && since_match {
+                records.push(record);
+            }
+        }
+
+        if let Some(limit) = filter.limit {
+            let skip = records.len().saturating_sub(limit);
+            records.drain(..skip);
+        }
+
+        Ok(records)
+    }
+}
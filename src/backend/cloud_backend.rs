@@ -0,0 +1,185 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::info;
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
+
+use crate::backend::panel_backend::PanelBackend;
+use crate::logging::logger::{redact, unredact, LogContext};
+use visonic::CircuitState;
+use visonic::{AuthedVisonic, Device, Event, ResStatus, State, StateChange, Visonic, VisonicErr};
+
+/// Session age after which the backend logs in again, as the watcher does by default.
+const SESSION_AGE: Duration = Duration::from_secs(600);
+
+/// Configured partition, `-1` stands for all of them.
+fn partition_context(visonic: &Visonic) -> LogContext {
+    match visonic.partition {
+        p if p >= 0 => LogContext::current().partition(p as i16),
+        _ => LogContext::current(),
+    }
+}
+
+fn failed(err: VisonicErr) -> StateChange {
+    StateChange {
+        process_token: None,
+        process_status: None,
+        error: Some(err),
+    }
+}
+
+/// Swaps the session, its tokens are kept out of the log while in use.
+fn replace(session: &mut Option<(AuthedVisonic, Instant)>, new: Option<(AuthedVisonic, Instant)>) {
+    if let Some((old, _)) = session.take() {
+        unredact(old.user_token());
+        unredact(old.session_token());
+    }
+    if let Some((visonic, _)) = &new {
+        redact(visonic.user_token());
+        redact(visonic.session_token());
+    }
+    *session = new;
+}
+
+/// tycomonitor cloud backend, calls share a session renewed as it ages or after a failed call.
+#[derive(Clone)]
+pub struct CloudBackend {
+    visonic: Visonic,
+    // locked across the login so concurrent calls log in once
+    session: Arc<Mutex<Option<(AuthedVisonic, Instant)>>>,
+}
+
+impl CloudBackend {
+    pub fn new(visonic: Visonic) -> Self {
+        CloudBackend {
+            visonic,
+            session: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn session(&self) -> Result<(AuthedVisonic, Instant), VisonicErr> {
+        let mut session = self.session.lock().await;
+        match &*session {
+            Some((visonic, since)) if since.elapsed() < SESSION_AGE => {
+                Ok((visonic.clone(), *since))
+            }
+            _ => {
+                let visonic = self.visonic.login().await?;
+                let since = Instant::now();
+                replace(&mut session, Some((visonic.clone(), since)));
+                Ok((visonic, since))
+            }
+        }
+    }
+
+    /// Drops the session logged in at `since` after a failed call, unless already renewed.
+    async fn expire(&self, since: Instant) {
+        let mut session = self.session.lock().await;
+        if session.as_ref().is_some_and(|(_, s)| *s == since) {
+            replace(&mut session, None);
+        }
+    }
+
+    async fn call<T, F, Fut>(&self, f: F) -> Result<T, VisonicErr>
+    where
+        F: FnOnce(AuthedVisonic) -> Fut + Send,
+        Fut: Future<Output = Result<T, VisonicErr>> + Send,
+    {
+        let (visonic, since) = self.session().await?;
+        let res = f(visonic).await;
+        if res.is_err() {
+            self.expire(since).await;
+        }
+        res
+    }
+}
+
+#[async_trait]
+impl PanelBackend for CloudBackend {
+    fn panel_id(&self) -> String {
+        self.visonic.panel_id.to_string()
+    }
+
+    fn partition(&self) -> Option<u16> {
+        u16::try_from(self.visonic.partition).ok()
+    }
+
+    async fn status(&self) -> Result<ResStatus, VisonicErr> {
+        self.call(|v| async move { v.status().await }).await
+    }
+
+    async fn set_state(&self, state: State) -> StateChange {
+        let (visonic, since) = match self.session().await {
+            Ok(session) => session,
+            Err(err) => return failed(err),
+        };
+        let change = partition_context(&self.visonic)
+            .scope(visonic.change_state(state))
+            .await;
+        if change.error.is_some() {
+            self.expire(since).await;
+        }
+        change
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>, VisonicErr> {
+        self.call(|v| async move { v.devices().await }).await
+    }
+
+    async fn bypass(&self, zone: u32, set: bool) -> StateChange {
+        let (visonic, since) = match self.session().await {
+            Ok(session) => session,
+            Err(err) => return failed(err),
+        };
+        let change = visonic.bypass_zone(zone, set).await;
+        if change.error.is_some() {
+            self.expire(since).await;
+        }
+        change
+    }
+
+    async fn events(&self) -> Result<Vec<Event>, VisonicErr> {
+        self.call(|v| async move { v.events().await }).await
+    }
+
+    async fn troubles(&self) -> Result<String, VisonicErr> {
+        self.call(|v| async move { v.troubles().await }).await
+    }
+
+    async fn alarms(&self) -> Result<String, VisonicErr> {
+        self.call(|v| async move { v.alarms().await }).await
+    }
+
+    async fn alerts(&self) -> Result<String, VisonicErr> {
+        self.call(|v| async move { v.alerts().await }).await
+    }
+
+    async fn panel_info(&self) -> Result<String, VisonicErr> {
+        self.call(|v| async move { v.panel_info().await }).await
+    }
+
+    fn circuit(&self) -> Option<watch::Receiver<CircuitState>> {
+        Some(self.visonic.circuit())
+    }
+
+    async fn wakeup_sms(&self) -> Result<Option<String>, VisonicErr> {
+        self.call(|v| async move { v.wakeup_sms().await.map(Some) })
+            .await
+    }
+
+    async fn diagnostics(&self) -> Result<(), VisonicErr> {
+        self.call(|visonic| async move {
+            let s = visonic.status_txt().await?;
+            info!("STATUS: {}", s);
+
+            let s = visonic.locations().await?;
+            info!("locations: {:?}", s);
+
+            Ok(())
+        })
+        .await
+    }
+}
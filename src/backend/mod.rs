@@ -0,0 +1,5 @@
+pub mod cloud_backend;
+pub mod panel_backend;
+pub mod powerlink_backend;
+pub mod powerlink_protocol;
+pub mod simulator_backend;
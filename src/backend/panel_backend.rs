@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use visonic::CircuitState;
+use visonic::{Device, Event, ResStatus, State, StateChange, VisonicErr};
+
+/// Panel access used by the gateway frontends, implemented by the cloud client and
+/// any alternative backend.
+#[async_trait]
+pub trait PanelBackend: Send + Sync {
+    /// Panel identifier reported in notifications.
+    fn panel_id(&self) -> String;
+
+    /// Partition state changes act on, none when they act on every partition.
+    fn partition(&self) -> Option<u16> {
+        None
+    }
+
+    async fn status(&self) -> Result<ResStatus, VisonicErr>;
+
+    /// Requests `state` and waits until the panel processed it.
+    async fn set_state(&self, state: State) -> StateChange;
+
+    async fn devices(&self) -> Result<Vec<Device>, VisonicErr>;
+
+    /// Bypasses (`set`) or restores `zone` and waits until the panel processed it.
+    async fn bypass(&self, zone: u32, set: bool) -> StateChange;
+
+    async fn events(&self) -> Result<Vec<Event>, VisonicErr>;
+
+    async fn troubles(&self) -> Result<String, VisonicErr>;
+
+    async fn alarms(&self) -> Result<String, VisonicErr>;
+
+    async fn alerts(&self) -> Result<String, VisonicErr>;
+
+    async fn panel_info(&self) -> Result<String, VisonicErr>;
+
+    /// Circuit breaker state of the backend, none when it has no remote API to protect.
+    fn circuit(&self) -> Option<watch::Receiver<CircuitState>> {
+        None
+    }
+
+    /// Handles backend specific commands, none when `command` is not one of them.
+    async fn control(&self, _command: &str) -> Option<Result<(), VisonicErr>> {
+        None
+    }
+
+    /// Wakeup SMS that makes a disconnected panel call home, for backends that support it.
+    async fn wakeup_sms(&self) -> Result<Option<String>, VisonicErr> {
+        Ok(None)
+    }
+
+    /// Logs backend specific details at startup, without side effects on the panel.
+    async fn diagnostics(&self) -> Result<(), VisonicErr> {
+        Ok(())
+    }
+}
@@ -0,0 +1,804 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::backend::panel_backend::PanelBackend;
+use crate::backend::powerlink_protocol::*;
+use visonic::{
+    Device, DeviceWarning, Event, Partition, PartitionStatus, ResProcessStatus, ResStatus, State,
+    StateChange, VisonicErr,
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const KEEPALIVE: Duration = Duration::from_secs(30);
+const CONFIRM_POLL: Duration = Duration::from_millis(250);
+
+fn default_baud_rate() -> u32 {
+    9600
+}
+
+fn default_command_timeout() -> u64 {
+    10
+}
+
+/// Local panel link, either a serial `device` or a TCP-serial bridge `address`.
+#[derive(Clone, Deserialize)]
+pub struct PowerLinkConfig {
+    pub panel_id: String,
+    pub device: Option<String>,
+    pub address: Option<String>,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    pub user_code: String,
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+}
+
+trait Link: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Link for T {}
+
+struct LinkState {
+    connected: bool,
+    state: State,
+    status: PartitionStatus,
+    ready: bool,
+    alarm: bool,
+    alarm_zone: Option<u32>,
+    open: Vec<u32>,
+    low_battery: Vec<u32>,
+    tampered: Vec<u32>,
+    enrolled: Vec<u32>,
+    bypassed: Vec<u32>,
+    events: Vec<Event>,
+    denied: bool,
+    /// Frames received since start, `acked`, `panel_seen` and `bypass_seen` are the numbers of
+    /// the last ACK, panel event and bypass event among them.
+    received: u64,
+    acked: u64,
+    panel_seen: u64,
+    bypass_seen: u64,
+}
+
+impl LinkState {
+    /// Whether the panel acknowledged a frame sent after the `sent`th frame was received and
+    /// reported `seen` after that, so the report reflects what was sent.
+    fn reported_since(&self, sent: u64, seen: u64) -> bool {
+        self.acked > sent && seen > self.acked
+    }
+}
+
+/// PowerMax / PowerMaster panel reached over its serial port, without the cloud.
+#[derive(Clone)]
+pub struct PowerLink {
+    config: PowerLinkConfig,
+    state: Arc<Mutex<LinkState>>,
+    commands: mpsc::Sender<Frame>,
+    processes: Arc<AtomicU64>,
+}
+
+impl PowerLinkConfig {
+    /// Starts the link task, it keeps reconnecting for the lifetime of the process.
+    pub fn start(&self) -> PowerLink {
+        let state = Arc::new(Mutex::new(LinkState {
+            connected: false,
+            state: State::DISARM,
+            status: PartitionStatus::NONE,
+            ready: false,
+            alarm: false,
+            alarm_zone: None,
+            open: vec![],
+            low_battery: vec![],
+            tampered: vec![],
+            enrolled: vec![],
+            bypassed: vec![],
+            events: vec![],
+            denied: false,
+            received: 0,
+            acked: 0,
+            panel_seen: 0,
+            bypass_seen: 0,
+        }));
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(run(self.clone(), state.clone(), rx));
+
+        PowerLink {
+            config: self.clone(),
+            state,
+            commands: tx,
+            processes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn link_name(&self) -> String {
+        self.device
+            .clone()
+            .or_else(|| self.address.clone())
+            .unwrap_or_default()
+    }
+
+    async fn connect(&self) -> std::io::Result<Box<dyn Link>> {
+        match (&self.device, &self.address) {
+            (Some(device), _) => {
+                let port = tokio_serial::new(device, self.baud_rate).open_native_async()?;
+                Ok(Box::new(port))
+            }
+            (None, Some(address)) => Ok(Box::new(TcpStream::connect(address).await?)),
+            (None, None) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "powerlink needs device or address",
+            )),
+        }
+    }
+}
+
+async fn run(config: PowerLinkConfig, state: Arc<Mutex<LinkState>>, mut rx: mpsc::Receiver<Frame>) {
+    loop {
+        match config.connect().await {
+            Ok(link) => {
+                info!("PowerLink connected to {}", config.link_name());
+                // commands queued while the link was down were already reported as failed
+                while rx.try_recv().is_ok() {}
+                if let Err(err) = serve(link, &state, &mut rx).await {
+                    error!("PowerLink {} failed: {}", config.link_name(), err);
+                }
+            }
+            Err(err) => error!(
+                "PowerLink connect to {} failed: {}",
+                config.link_name(),
+                err
+            ),
+        }
+
+        state.lock().unwrap().connected = false;
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn serve(
+    link: Box<dyn Link>,
+    state: &Arc<Mutex<LinkState>>,
+    rx: &mut mpsc::Receiver<Frame>,
+) -> std::io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(link);
+    let mut buf: Vec<u8> = vec![];
+    let mut chunk = [0u8; 256];
+    let mut keepalive = tokio::time::interval(KEEPALIVE);
+
+    loop {
+        tokio::select! {
+            n = reader.read(&mut chunk) => {
+                let n = n?;
+                if n == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                buf.extend_from_slice(&chunk[..n]);
+                while let Some(frame) = Frame::decode(&mut buf) {
+                    debug!("PowerLink received {:02X?}", frame);
+                    // the panel expects every message but its own ACK to be acknowledged
+                    if frame.kind != MSG_ACK {
+                        writer.write_all(&Frame::ack().encode()).await?;
+                    }
+                    handle(&mut state.lock().unwrap(), frame);
+                }
+            }
+            Some(frame) = rx.recv() => {
+                writer.write_all(&frame.encode()).await?;
+            }
+            _ = keepalive.tick() => {
+                writer.write_all(&Frame::status_request().encode()).await?;
+            }
+        }
+    }
+}
+
+fn handle(state: &mut LinkState, frame: Frame) {
+    state.connected = true;
+    state.received += 1;
+    let seen = state.received;
+
+    match frame.kind {
+        MSG_ACK => state.acked = seen,
+        MSG_ACCESS_DENIED => {
+            warn!("PowerLink access denied");
+            state.denied = true;
+        }
+        MSG_EVENT => match frame.data[1] {
+            EVENT_PANEL => {
+                state.panel_seen = seen;
+                let flags = frame.data[3];
+                let (panel_state, status) = system_status(frame.data[2]);
+                if let Some(panel_state) = panel_state {
+                    if panel_state != state.state {
+                        let label = match panel_state {
+                            State::DISARM => "DISARM",
+                            _ => "ARM",
+                        };
+                        log(state, label, format!("{:?}", panel_state), None);
+                    }
+                    state.state = panel_state;
+                }
+                state.status = status;
+                state.ready = flags & FLAG_READY != 0;
+
+                let alarm = flags & FLAG_ALARM != 0;
+                if alarm && !state.alarm {
+                    let zone = Some(frame.data[4] as u32).filter(|z| *z > 0);
+                    state.alarm_zone = zone;
+                    log(state, "ALARM", "Alarm".to_string(), zone);
+                }
+                state.alarm = alarm;
+                if flags & FLAG_TROUBLE != 0 {
+                    debug!("PowerLink panel reports trouble");
+                }
+            }
+            EVENT_ZONES => {
+                state.open = zones(&frame.data[2..6]);
+                state.low_battery = zones(&frame.data[6..10]);
+            }
+            EVENT_TAMPER => {
+                state.tampered = zones(&frame.data[6..10]);
+            }
+            EVENT_BYPASS => {
+                state.bypass_seen = seen;
+                state.enrolled = zones(&frame.data[2..6]);
+                state.bypassed = zones(&frame.data[6..10]);
+            }
+            _ => (),
+        },
+        _ => (),
+    }
+}
+
+fn log(state: &mut LinkState, label: &str, description: String, zone: Option<u32>) {
+    let event = Event {
+        event: state.events.len() as u64 + 1,
+        type_id: None,
+        label: Some(label.to_string()),
+        description: Some(description),
+        appointment: None,
+        datetime: Some(Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()),
+        device_type: zone.map(|_| "ZONE".to_string()),
+        zone,
+        partitions: vec![1],
+    };
+    state.events.push(event);
+}
+
+impl PowerLink {
+    fn failed(token: String, reason: String) -> StateChange {
+        StateChange {
+            process_token: Some(token.to_string()),
+            process_status: Some(ResProcessStatus {
+                token,
+                status: "failed".to_string(),
+                error: Some(reason.to_string()),
+            }),
+            error: Some(VisonicErr::Backend(reason)),
+        }
+    }
+
+    /// Sends `frame` followed by a status request, none of them while the link is down. Returns
+    /// the number of frames received before, to tell the answers from older reports.
+    async fn send(&self, frame: Frame) -> Result<u64, String> {
+        let sent = {
+            let mut state = self.state.lock().unwrap();
+            if !state.connected {
+                return Err("link is not connected".to_string());
+            }
+            state.denied = false;
+            state.received
+        };
+        for frame in [frame, Frame::status_request()] {
+            if self.commands.send(frame).await.is_err() {
+                return Err("link is not running".to_string());
+            }
+        }
+        Ok(sent)
+    }
+
+    fn zones(&self) -> Vec<u32> {
+        let state = self.state.lock().unwrap();
+        let mut zones = state.enrolled.clone();
+        if zones.is_empty() {
+            zones.extend(state.open.iter());
+            zones.extend(state.bypassed.iter());
+            zones.sort_unstable();
+            zones.dedup();
+        }
+        zones
+    }
+}
+
+#[async_trait]
+impl PanelBackend for PowerLink {
+    fn panel_id(&self) -> String {
+        self.config.panel_id.to_string()
+    }
+
+    async fn status(&self) -> Result<ResStatus, VisonicErr> {
+        let state = self.state.lock().unwrap();
+        let status = match state.alarm {
+            true => PartitionStatus::ALARM,
+            false => state.status.clone(),
+        };
+
+        Ok(ResStatus {
+            connected: state.connected,
+            partitions: vec![Partition {
+                id: 1,
+                state: state.state.clone(),
+                status,
+                ready: state.ready,
+            }],
+        })
+    }
+
+    async fn set_state(&self, target: State) -> StateChange {
+        let token = format!("pl-{}", self.processes.fetch_add(1, Ordering::SeqCst) + 1);
+
+        let code = match user_code(&self.config.user_code) {
+            Some(code) => code,
+            None => return PowerLink::failed(token, "user_code must be 4 digits".to_string()),
+        };
+
+        let mode = match arm_mode(&target) {
+            Some(mode) => mode,
+            None => return PowerLink::failed(token, format!("{} is not supported", target)),
+        };
+
+        let sent = match self.send(Frame::arm(mode, code)).await {
+            Ok(sent) => sent,
+            Err(reason) => return PowerLink::failed(token, reason),
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(self.config.command_timeout);
+        while Instant::now() < deadline {
+            tokio::time::sleep(CONFIRM_POLL).await;
+            let state = self.state.lock().unwrap();
+            if state.denied {
+                return PowerLink::failed(token, "access denied".to_string());
+            }
+            if state.reported_since(sent, state.panel_seen) && arm_mode(&state.state) == Some(mode)
+            {
+                return StateChange {
+                    process_token: Some(token.to_string()),
+                    process_status: Some(ResProcessStatus {
+                        token,
+                        status: "succeeded".to_string(),
+                        error: None,
+                    }),
+                    error: None,
+                };
+            }
+        }
+
+        PowerLink::failed(token, "panel did not confirm the state".to_string())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>, VisonicErr> {
+        let zones = self.zones();
+        let state = self.state.lock().unwrap();
+        let devices = zones
+            .iter()
+            .map(|zone| Device {
+                id: *zone as u64,
+                zone: Some(*zone),
+                location: None,
+                device_type: Some("ZONE".to_string()),
+                subtype: None,
+                partitions: vec![1],
+                bypass: state.bypassed.contains(zone),
+                warnings: match state.open.contains(zone) {
+                    true => vec![DeviceWarning {
+                        kind: "OPENED".to_string(),
+                    }],
+                    false => vec![],
+                },
+            })
+            .collect();
+        Ok(devices)
+    }
+
+    async fn bypass(&self, zone: u32, set: bool) -> StateChange {
+        let token = format!("pl-{}", self.processes.fetch_add(1, Ordering::SeqCst) + 1);
+
+        let code = match user_code(&self.config.user_code) {
+            Some(code) => code,
+            None => return PowerLink::failed(token, "user_code must be 4 digits".to_string()),
+        };
+        let bitmap = match zone_bitmap(zone) {
+            Some(bitmap) => bitmap,
+            None => return PowerLink::failed(token, format!("unknown zone {}", zone)),
+        };
+        let frame = match set {
+            true => Frame::bypass(code, bitmap, [0; 4]),
+            false => Frame::bypass(code, [0; 4], bitmap),
+        };
+
+        let sent = match self.send(frame).await {
+            Ok(sent) => sent,
+            Err(reason) => return PowerLink::failed(token, reason),
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(self.config.command_timeout);
+        while Instant::now() < deadline {
+            tokio::time::sleep(CONFIRM_POLL).await;
+            let state = self.state.lock().unwrap();
+            if state.denied {
+                return PowerLink::failed(token, "access denied".to_string());
+            }
+            if state.reported_since(sent, state.bypass_seen)
+                && state.bypassed.contains(&zone) == set
+            {
+                return StateChange {
+                    process_token: Some(token.to_string()),
+                    process_status: Some(ResProcessStatus {
+                        token,
+                        status: "succeeded".to_string(),
+                        error: None,
+                    }),
+                    error: None,
+                };
+            }
+        }
+
+        PowerLink::failed(token, "panel did not confirm the bypass".to_string())
+    }
+
+    async fn events(&self) -> Result<Vec<Event>, VisonicErr> {
+        Ok(self.state.lock().unwrap().events.clone())
+    }
+
+    async fn troubles(&self) -> Result<String, VisonicErr> {
+        let state = self.state.lock().unwrap();
+        let low_battery = state
+            .low_battery
+            .iter()
+            .map(|zone| json!({ "zone": zone, "trouble_type": "LOW_BATTERY" }));
+        let tampered = state
+            .tampered
+            .iter()
+            .map(|zone| json!({ "zone": zone, "trouble_type": "TAMPER" }));
+        Ok(Value::Array(low_battery.chain(tampered).collect()).to_string())
+    }
+
+    async fn alarms(&self) -> Result<String, VisonicErr> {
+        let state = self.state.lock().unwrap();
+        let alarms = match state.alarm {
+            true => {
+                vec![json!({ "zone": state.alarm_zone, "alarm_type": "ALARM", "partition": 1 })]
+            }
+            false => vec![],
+        };
+        Ok(Value::Array(alarms).to_string())
+    }
+
+    async fn alerts(&self) -> Result<String, VisonicErr> {
+        Ok("[]".to_string())
+    }
+
+    async fn panel_info(&self) -> Result<String, VisonicErr> {
+        Ok(json!({
+            "serial": self.config.panel_id,
+            "model": "POWERMAX",
+            "link": self.config.link_name(),
+        })
+        .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_serial::{SerialPort, SerialStream};
+
+    use super::*;
+
+    /// PowerMax emulator answering status, arm and bypass requests, nothing at all once `mute`.
+    struct Emulator {
+        code: [u8; 2],
+        status: u8,
+        ready: bool,
+        open: [u8; 4],
+        enrolled: [u8; 4],
+        bypassed: [u8; 4],
+        acks: usize,
+        mute: bool,
+    }
+
+    impl Emulator {
+        fn new(code: &str) -> Emulator {
+            Emulator {
+                code: user_code(code).unwrap(),
+                status: 0x00,
+                ready: true,
+                open: [0x04, 0, 0, 0],
+                enrolled: [0x0F, 0, 0, 0],
+                bypassed: [0; 4],
+                acks: 0,
+                mute: false,
+            }
+        }
+
+        fn event(kind: u8, payload: &[u8]) -> Frame {
+            let mut data = vec![0, kind];
+            data.extend_from_slice(payload);
+            data.resize(10, 0);
+            data.push(0x43);
+            Frame::new(MSG_EVENT, data)
+        }
+
+        fn panel(&self) -> Frame {
+            let flags = match self.ready {
+                true => FLAG_READY,
+                false => 0,
+            };
+            Emulator::event(EVENT_PANEL, &[self.status, flags])
+        }
+
+        fn zones(&self) -> Frame {
+            Emulator::event(EVENT_ZONES, &self.open)
+        }
+
+        fn bypasses(&self) -> Frame {
+            let mut payload = self.enrolled.to_vec();
+            payload.extend_from_slice(&self.bypassed);
+            Emulator::event(EVENT_BYPASS, &payload)
+        }
+
+        fn receive(&mut self, frame: Frame) -> Vec<Frame> {
+            let denied = Frame::new(MSG_ACCESS_DENIED, vec![]);
+            match frame.kind {
+                _ if self.mute => vec![],
+                MSG_ACK => {
+                    self.acks += 1;
+                    vec![]
+                }
+                MSG_STATUS => vec![Frame::ack(), self.panel(), self.zones(), self.bypasses()],
+                MSG_ARM if frame.data[3..5] != self.code[..] => vec![denied],
+                MSG_ARM => {
+                    self.status = frame.data[2];
+                    vec![Frame::ack(), self.panel()]
+                }
+                MSG_BYPASS if frame.data[0..2] != self.code[..] => vec![denied],
+                MSG_BYPASS => {
+                    for i in 0..4 {
+                        self.bypassed[i] =
+                            (self.bypassed[i] | frame.data[2 + i]) & !frame.data[6 + i];
+                    }
+                    vec![Frame::ack(), self.bypasses()]
+                }
+                _ => vec![],
+            }
+        }
+    }
+
+    /// Answers the frames the link writes to `stream` until it is closed.
+    async fn emulate<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
+        panel: Arc<Mutex<Emulator>>,
+    ) {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 256];
+        loop {
+            let n = stream.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            while let Some(frame) = Frame::decode(&mut buf) {
+                let replies = panel.lock().unwrap().receive(frame);
+                for reply in replies {
+                    stream.write_all(&reply.encode()).await.unwrap();
+                }
+            }
+        }
+    }
+
+    /// Emulator behind a TCP-serial bridge, returns its address.
+    async fn emulator(panel: Emulator) -> (String, Arc<Mutex<Emulator>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let panel = Arc::new(Mutex::new(panel));
+        let emulated = panel.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            emulate(stream, emulated).await
+        });
+        (address, panel)
+    }
+
+    fn config(device: Option<String>, address: Option<String>, user_code: &str) -> PowerLinkConfig {
+        PowerLinkConfig {
+            panel_id: "local".to_string(),
+            device,
+            address,
+            baud_rate: default_baud_rate(),
+            user_code: user_code.to_string(),
+            command_timeout: 2,
+        }
+    }
+
+    /// Waits for the first status answer of the panel.
+    async fn connected(link: PowerLink) -> PowerLink {
+        // connected on the first frame, let the rest of the status answer arrive
+        for _ in 0..100 {
+            if link.status().await.unwrap().connected {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                return link;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("link did not connect");
+    }
+
+    async fn connect(panel: Emulator, user_code: &str) -> (PowerLink, Arc<Mutex<Emulator>>) {
+        let (address, panel) = emulator(panel).await;
+        let link = config(None, Some(address), user_code).start();
+        (connected(link).await, panel)
+    }
+
+    fn partition(status: ResStatus) -> Partition {
+        status.partitions.into_iter().next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_status_and_zones() {
+        let (link, _) = connect(Emulator::new("1234"), "1234").await;
+
+        let partition = partition(link.status().await.unwrap());
+        assert_eq!(partition.state, State::DISARM);
+        assert_eq!(partition.status, PartitionStatus::NONE);
+        assert!(partition.ready);
+
+        let devices = link.devices().await.unwrap();
+        let zones: Vec<Option<u32>> = devices.iter().map(|d| d.zone).collect();
+        assert_eq!(zones, vec![Some(1), Some(2), Some(3), Some(4)]);
+        assert!(devices[2].warnings.iter().any(|w| w.kind == "OPENED"));
+        assert!(devices[0].warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn acknowledges_panel_messages_but_acks() {
+        let (_link, panel) = connect(Emulator::new("1234"), "1234").await;
+
+        // panel, zones and bypass events of the status answer, not its ACK
+        assert_eq!(panel.lock().unwrap().acks, 3);
+    }
+
+    #[tokio::test]
+    async fn arms_and_disarms() {
+        let (link, panel) = connect(Emulator::new("1234"), "1234").await;
+
+        let change = link.set_state(State::AWAY).await;
+        assert!(change.error.is_none(), "{:?}", change.error);
+        assert_eq!(change.process_status.unwrap().status, "succeeded");
+        assert_eq!(panel.lock().unwrap().status, 0x05);
+        assert_eq!(partition(link.status().await.unwrap()).state, State::AWAY);
+
+        let change = link.set_state(State::DISARM).await;
+        assert!(change.error.is_none(), "{:?}", change.error);
+        assert_eq!(panel.lock().unwrap().status, 0x00);
+        assert_eq!(partition(link.status().await.unwrap()).state, State::DISARM);
+    }
+
+    #[tokio::test]
+    async fn confirms_night_armed_as_stay() {
+        let (link, panel) = connect(Emulator::new("1234"), "1234").await;
+
+        let change = link.set_state(State::NIGHT).await;
+        assert!(change.error.is_none(), "{:?}", change.error);
+        assert_eq!(panel.lock().unwrap().status, 0x04);
+        assert_eq!(partition(link.status().await.unwrap()).state, State::STAY);
+    }
+
+    #[tokio::test]
+    async fn fails_on_access_denied() {
+        let (link, panel) = connect(Emulator::new("4321"), "1234").await;
+
+        let change = link.set_state(State::AWAY).await;
+        assert_eq!(change.process_status.unwrap().status, "failed");
+        assert!(change.error.unwrap().to_string().contains("access denied"));
+        assert_eq!(panel.lock().unwrap().status, 0x00);
+
+        let change = link.bypass(3, true).await;
+        assert!(change.error.unwrap().to_string().contains("access denied"));
+
+        // denials are acknowledged like any other panel message, each command is followed by
+        // a status request answered with three events
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(panel.lock().unwrap().acks, 3 + 2 * (1 + 3));
+    }
+
+    #[tokio::test]
+    async fn bypasses_and_restores_zones() {
+        let (link, panel) = connect(Emulator::new("1234"), "1234").await;
+
+        let change = link.bypass(3, true).await;
+        assert!(change.error.is_none(), "{:?}", change.error);
+        assert_eq!(panel.lock().unwrap().bypassed, [0x04, 0, 0, 0]);
+        let bypassed: Vec<u64> = link
+            .devices()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|d| d.bypass)
+            .map(|d| d.id)
+            .collect();
+        assert_eq!(bypassed, vec![3]);
+
+        let change = link.bypass(3, false).await;
+        assert!(change.error.is_none(), "{:?}", change.error);
+        assert_eq!(panel.lock().unwrap().bypassed, [0; 4]);
+        assert!(link.devices().await.unwrap().iter().all(|d| !d.bypass));
+    }
+
+    #[tokio::test]
+    async fn talks_to_a_serial_device() {
+        let (panel, device) = SerialStream::pair().unwrap();
+        let emulated = Arc::new(Mutex::new(Emulator::new("1234")));
+        tokio::spawn(emulate(panel, emulated.clone()));
+
+        // the device end stays open so the panel end does not see it hang up
+        let link = config(device.name(), None, "1234").start();
+        let link = connected(link).await;
+        assert_eq!(emulated.lock().unwrap().acks, 3);
+
+        let change = link.set_state(State::AWAY).await;
+        assert!(change.error.is_none(), "{:?}", change.error);
+        assert_eq!(partition(link.status().await.unwrap()).state, State::AWAY);
+        drop(device);
+    }
+
+    #[tokio::test]
+    async fn refuses_commands_while_not_connected() {
+        // nothing listens on the address of a dropped listener
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let link = config(None, Some(address), "1234").start();
+
+        let change = link.set_state(State::AWAY).await;
+        assert!(change.error.unwrap().to_string().contains("not connected"));
+        let change = link.bypass(3, true).await;
+        assert!(change.error.unwrap().to_string().contains("not connected"));
+        // nothing was queued for the next connection
+        assert_eq!(link.commands.capacity(), 16);
+    }
+
+    #[tokio::test]
+    async fn confirms_only_reports_after_the_command() {
+        let mut panel = Emulator::new("1234");
+        panel.status = 0x05;
+        panel.bypassed = [0x04, 0, 0, 0];
+        let (link, panel) = connect(panel, "1234").await;
+        assert_eq!(partition(link.status().await.unwrap()).state, State::AWAY);
+
+        // the panel already is in the requested state but never answers the command
+        panel.lock().unwrap().mute = true;
+        let change = link.set_state(State::AWAY).await;
+        assert!(change
+            .error
+            .unwrap()
+            .to_string()
+            .contains("did not confirm"));
+        let change = link.bypass(3, true).await;
+        assert!(change
+            .error
+            .unwrap()
+            .to_string()
+            .contains("did not confirm"));
+    }
+}
@@ -0,0 +1,198 @@
+//! PowerMax / PowerMaster serial protocol framing.
+//!
+//! Every frame is `0x0D <type> <data..> <checksum> 0x0A`, the checksum covers type and data.
+
+use visonic::{PartitionStatus, State};
+
+pub const PREAMBLE: u8 = 0x0D;
+pub const POSTAMBLE: u8 = 0x0A;
+
+pub const MSG_ACK: u8 = 0x02;
+pub const MSG_TIMEOUT: u8 = 0x06;
+pub const MSG_ACCESS_DENIED: u8 = 0x08;
+pub const MSG_ARM: u8 = 0xA1;
+pub const MSG_STATUS: u8 = 0xA2;
+pub const MSG_EVENT: u8 = 0xA5;
+pub const MSG_BYPASS: u8 = 0xAA;
+
+pub const EVENT_ZONES: u8 = 0x01;
+pub const EVENT_TAMPER: u8 = 0x02;
+pub const EVENT_PANEL: u8 = 0x04;
+pub const EVENT_BYPASS: u8 = 0x06;
+
+pub const FLAG_READY: u8 = 0x01;
+pub const FLAG_TROUBLE: u8 = 0x04;
+pub const FLAG_ALARM: u8 = 0x80;
+
+const MAX_ZONES: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+pub fn checksum(bytes: &[u8]) -> u8 {
+    let sum: u32 = bytes.iter().map(|b| *b as u32).sum();
+    match 0xFF - (sum % 0xFF) {
+        0xFF => 0x00,
+        c => c as u8,
+    }
+}
+
+/// Data length following the type byte, host and panel `0xAx` messages are fixed size.
+fn data_len(kind: u8) -> Option<usize> {
+    match kind {
+        MSG_ACK | MSG_TIMEOUT | MSG_ACCESS_DENIED => Some(0),
+        0xA0..=0xAF => Some(11),
+        _ => None,
+    }
+}
+
+impl Frame {
+    pub fn new(kind: u8, data: Vec<u8>) -> Frame {
+        Frame { kind, data }
+    }
+
+    pub fn ack() -> Frame {
+        Frame::new(MSG_ACK, vec![])
+    }
+
+    pub fn status_request() -> Frame {
+        Frame::new(MSG_STATUS, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x43])
+    }
+
+    pub fn arm(mode: u8, user_code: [u8; 2]) -> Frame {
+        Frame::new(
+            MSG_ARM,
+            vec![0, 0, mode, user_code[0], user_code[1], 0, 0, 0, 0, 0, 0x43],
+        )
+    }
+
+    /// Bypasses the zones set in `enable` and restores the zones set in `disable`.
+    pub fn bypass(user_code: [u8; 2], enable: [u8; 4], disable: [u8; 4]) -> Frame {
+        let mut data = vec![user_code[0], user_code[1]];
+        data.extend_from_slice(&enable);
+        data.extend_from_slice(&disable);
+        data.push(0x43);
+        Frame::new(MSG_BYPASS, data)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![self.kind];
+        body.extend_from_slice(&self.data);
+
+        let mut bytes = vec![PREAMBLE];
+        bytes.extend_from_slice(&body);
+        bytes.push(checksum(&body));
+        bytes.push(POSTAMBLE);
+        bytes
+    }
+
+    /// Takes the first complete frame from `buf`, dropping garbage and corrupted frames.
+    pub fn decode(buf: &mut Vec<u8>) -> Option<Frame> {
+        loop {
+            match buf.iter().position(|b| *b == PREAMBLE) {
+                Some(start) => {
+                    buf.drain(..start);
+                }
+                None => {
+                    buf.clear();
+                    return None;
+                }
+            }
+            if buf.len() < 2 {
+                return None;
+            }
+
+            let end = match data_len(buf[1]) {
+                Some(len) => 2 + len + 1,
+                // unknown type, resynchronize on the postamble
+                None => match buf.iter().position(|b| *b == POSTAMBLE) {
+                    Some(pos) if pos >= 3 => pos,
+                    Some(_) => {
+                        buf.drain(..1);
+                        continue;
+                    }
+                    None => return None,
+                },
+            };
+            if buf.len() < end + 1 {
+                return None;
+            }
+
+            let body = &buf[1..end - 1];
+            if buf[end] == POSTAMBLE && checksum(body) == buf[end - 1] {
+                let frame = Frame::new(body[0], body[1..].to_vec());
+                buf.drain(..end + 1);
+                return Some(frame);
+            }
+            // misaligned or corrupted, look for the next preamble
+            buf.drain(..1);
+        }
+    }
+}
+
+/// `0xA1` arm mode of a requested state, PowerMax has no night mode so it arms home.
+pub fn arm_mode(state: &State) -> Option<u8> {
+    match state {
+        State::DISARM => Some(0x00),
+        State::STAY | State::HOME | State::NIGHT => Some(0x04),
+        State::AWAY => Some(0x05),
+        State::STAY_INSTANT | State::HOME_INSTANT | State::NIGHT_INSTANT => Some(0x14),
+        State::AWAY_INSTANT => Some(0x15),
+        _ => None,
+    }
+}
+
+/// Four digit user code packed as BCD.
+pub fn user_code(code: &str) -> Option<[u8; 2]> {
+    let digits: Vec<u8> = code
+        .chars()
+        .map(|c| c.to_digit(10).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    match digits.as_slice() {
+        [a, b, c, d] => Some([a << 4 | b, c << 4 | d]),
+        _ => None,
+    }
+}
+
+/// Zone numbers set in a little endian bitmap, zone 1 is the lowest bit.
+pub fn zones(bitmap: &[u8]) -> Vec<u32> {
+    (0..MAX_ZONES.min(bitmap.len() as u32 * 8))
+        .filter(|bit| bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+        .map(|bit| bit + 1)
+        .collect()
+}
+
+/// Little endian bitmap with the single `zone` set, inverse of `zones`.
+pub fn zone_bitmap(zone: u32) -> Option<[u8; 4]> {
+    let mut bitmap = [0u8; 4];
+    match zone {
+        1..=32 => {
+            let bit = zone - 1;
+            bitmap[(bit / 8) as usize] = 1 << (bit % 8);
+            Some(bitmap)
+        }
+        _ => None,
+    }
+}
+
+/// Panel state and status of a `0xA5 0x04` system status byte.
+pub fn system_status(status: u8) -> (Option<State>, PartitionStatus) {
+    match status {
+        0x00 => (Some(State::DISARM), PartitionStatus::NONE),
+        0x01 => (Some(State::STAY), PartitionStatus::EXIT_DELAY),
+        0x02 => (Some(State::AWAY), PartitionStatus::EXIT_DELAY),
+        0x03 => (None, PartitionStatus::ENTRY_DELAY),
+        0x04 | 0x0A => (Some(State::STAY), PartitionStatus::NONE),
+        0x05 | 0x0B => (Some(State::AWAY), PartitionStatus::NONE),
+        0x14 => (Some(State::STAY_INSTANT), PartitionStatus::NONE),
+        0x15 => (Some(State::AWAY_INSTANT), PartitionStatus::NONE),
+        0x06 => (None, PartitionStatus::USER_TEST),
+        0x07 => (None, PartitionStatus::DOWNLOADING),
+        0x08 => (None, PartitionStatus::PROGRAMMING),
+        0x09 => (None, PartitionStatus::INSTALLER),
+        _ => (None, PartitionStatus::Unknown(format!("0x{:02X}", status))),
+    }
+}
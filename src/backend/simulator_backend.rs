@@ -0,0 +1,684 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+
+use crate::backend::panel_backend::PanelBackend;
+use visonic::{
+    Device, DeviceWarning, Event, Partition, PartitionStatus, ResProcessStatus, ResStatus, State,
+    StateChange, VisonicErr,
+};
+
+fn default_delay() -> u64 {
+    30
+}
+
+fn default_model() -> String {
+    "POWERMASTER".to_string()
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SimulatorConfig {
+    pub panel_id: String,
+    #[serde(default = "default_delay")]
+    pub exit_delay: u64,
+    #[serde(default = "default_delay")]
+    pub entry_delay: u64,
+    pub partitions: Vec<u16>,
+    /// Reported panel model, instant and latchkey arming depend on it.
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[serde(default)]
+    pub zones: Vec<SimZone>,
+    #[serde(default)]
+    pub troubles: Vec<SimTrouble>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneKind {
+    /// Starts the entry delay when armed.
+    Delay,
+    /// Alarms immediately when armed.
+    #[default]
+    Perimeter,
+    /// Ignored in STAY and NIGHT, follows a running entry delay in AWAY.
+    Interior,
+    /// Alarms in any state.
+    TwentyFourHour,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SimZone {
+    pub id: u32,
+    pub location: String,
+    pub partition: u16,
+    #[serde(default)]
+    pub kind: ZoneKind,
+    #[serde(default)]
+    pub open: bool,
+    #[serde(default)]
+    pub bypass: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SimTrouble {
+    pub zone: u32,
+    pub trouble_type: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    ExitDelay(Instant),
+    EntryDelay(Instant),
+    Alarm,
+}
+
+struct SimPartition {
+    id: u16,
+    state: State,
+    phase: Phase,
+}
+
+struct SimState {
+    partitions: Vec<SimPartition>,
+    zones: Vec<SimZone>,
+    troubles: Vec<SimTrouble>,
+    alarms: Vec<Value>,
+    events: Vec<Event>,
+    processes: u64,
+}
+
+/// Virtual panel for demos and dashboard work, time only advances when the panel is queried.
+#[derive(Clone)]
+pub struct Simulator {
+    config: SimulatorConfig,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimulatorConfig {
+    pub fn start(&self) -> Simulator {
+        let partitions = self
+            .partitions
+            .iter()
+            .map(|id| SimPartition {
+                id: *id,
+                state: State::DISARM,
+                phase: Phase::Idle,
+            })
+            .collect();
+
+        Simulator {
+            config: self.clone(),
+            state: Arc::new(Mutex::new(SimState {
+                partitions,
+                zones: self.zones.clone(),
+                troubles: self.troubles.clone(),
+                alarms: vec![],
+                events: vec![],
+                processes: 0,
+            })),
+        }
+    }
+}
+
+/// Interior zones are armed in away modes only.
+fn is_away(state: &State) -> bool {
+    matches!(state, State::AWAY | State::AWAY_INSTANT | State::LATCHKEY)
+}
+
+/// Instant modes skip the entry delay.
+fn is_instant(state: &State) -> bool {
+    matches!(
+        state,
+        State::AWAY_INSTANT | State::STAY_INSTANT | State::HOME_INSTANT | State::NIGHT_INSTANT
+    )
+}
+
+impl SimState {
+    fn log(&mut self, label: &str, description: String, zone: Option<u32>, partition: u16) {
+        let event = Event {
+            event: self.events.len() as u64 + 1,
+            type_id: None,
+            label: Some(label.to_string()),
+            description: Some(description),
+            appointment: Some("Simulator".to_string()),
+            datetime: Some(Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()),
+            device_type: zone.map(|_| "ZONE".to_string()),
+            zone,
+            partitions: vec![partition as i16],
+        };
+        self.events.push(event);
+    }
+
+    fn alarm(&mut self, partition: usize, zone: Option<&SimZone>) {
+        let id = self.partitions[partition].id;
+        self.partitions[partition].phase = Phase::Alarm;
+        self.alarms.push(json!({
+            "zone": zone.map(|z| z.id),
+            "location": zone.map(|z| z.location.to_string()),
+            "alarm_type": "BURGLARY",
+            "partition": id,
+            "datetime": Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        }));
+        info!("Simulator partition {} in alarm", id);
+        self.log(
+            "ALARM",
+            "Burglary alarm".to_string(),
+            zone.map(|z| z.id),
+            id,
+        );
+    }
+
+    /// Moves delays that elapsed by `now` to their next phase.
+    fn advance(&mut self, now: Instant) {
+        for i in 0..self.partitions.len() {
+            match self.partitions[i].phase {
+                Phase::ExitDelay(until) if until <= now => {
+                    self.partitions[i].phase = Phase::Idle;
+                }
+                Phase::EntryDelay(until) if until <= now => self.alarm(i, None),
+                _ => (),
+            }
+        }
+    }
+
+    fn open_zones(&self, partition: u16) -> Vec<&SimZone> {
+        self.zones
+            .iter()
+            .filter(|z| z.partition == partition && z.open && !z.bypass)
+            .collect()
+    }
+
+    fn zone_opened(&mut self, zone: SimZone, entry_delay: Duration) {
+        let i = match self.partitions.iter().position(|p| p.id == zone.partition) {
+            Some(i) => i,
+            None => return,
+        };
+        let armed_state = self.partitions[i].state.clone();
+        let phase = self.partitions[i].phase;
+        let armed = armed_state != State::DISARM && phase == Phase::Idle;
+        let entry = matches!(phase, Phase::EntryDelay(_));
+
+        match zone.kind {
+            ZoneKind::TwentyFourHour => self.alarm(i, Some(&zone)),
+            _ if !armed && !entry => (),
+            ZoneKind::Delay if is_instant(&armed_state) => self.alarm(i, Some(&zone)),
+            ZoneKind::Delay if !entry => {
+                self.partitions[i].phase = Phase::EntryDelay(Instant::now() + entry_delay);
+            }
+            ZoneKind::Delay => (),
+            ZoneKind::Interior if entry || !is_away(&armed_state) => {}
+            ZoneKind::Interior | ZoneKind::Perimeter => self.alarm(i, Some(&zone)),
+        }
+    }
+}
+
+impl Simulator {
+    fn set_zone(&self, id: &str, open: bool) -> Result<(), VisonicErr> {
+        let mut state = self.state.lock().unwrap();
+        state.advance(Instant::now());
+
+        let zone = state
+            .zones
+            .iter_mut()
+            .find(|z| z.id.to_string().eq(id))
+            .ok_or_else(|| VisonicErr::Backend(format!("unknown zone {}", id)))?;
+        let changed = zone.open != open;
+        zone.open = open;
+        let zone = zone.clone();
+
+        if changed {
+            let label = if open { "OPEN" } else { "CLOSE" };
+            state.log(
+                label,
+                zone.location.to_string(),
+                Some(zone.id),
+                zone.partition,
+            );
+            if open && !zone.bypass {
+                state.zone_opened(zone, Duration::from_secs(self.config.entry_delay));
+            }
+        }
+        Ok(())
+    }
+
+    fn raise_trouble(&self, id: &str, trouble_type: &str) -> Result<(), VisonicErr> {
+        let mut state = self.state.lock().unwrap();
+        let zone = state
+            .zones
+            .iter()
+            .find(|z| z.id.to_string().eq(id))
+            .cloned()
+            .ok_or_else(|| VisonicErr::Backend(format!("unknown zone {}", id)))?;
+
+        state.troubles.push(SimTrouble {
+            zone: zone.id,
+            trouble_type: trouble_type.to_string(),
+        });
+        state.log(
+            "TROUBLE",
+            trouble_type.to_string(),
+            Some(zone.id),
+            zone.partition,
+        );
+        Ok(())
+    }
+
+    fn bypass_zone(&self, id: u32, set: bool) -> Result<(), VisonicErr> {
+        let mut state = self.state.lock().unwrap();
+        state.advance(Instant::now());
+
+        let i = state
+            .zones
+            .iter()
+            .position(|z| z.id == id)
+            .ok_or_else(|| VisonicErr::Backend(format!("unknown zone {}", id)))?;
+        let partition = state.zones[i].partition;
+        let armed = state
+            .partitions
+            .iter()
+            .any(|p| p.id == partition && p.state != State::DISARM);
+        if armed {
+            return Err(VisonicErr::Backend(format!(
+                "zone {} can only be bypassed while disarmed",
+                id
+            )));
+        }
+        let zone = &mut state.zones[i];
+        zone.bypass = set;
+        let zone = zone.clone();
+
+        let label = if set { "BYPASS" } else { "UNBYPASS" };
+        state.log(
+            label,
+            zone.location.to_string(),
+            Some(zone.id),
+            zone.partition,
+        );
+        Ok(())
+    }
+
+    fn restore(&self, id: &str) -> Result<(), VisonicErr> {
+        let mut state = self.state.lock().unwrap();
+        let zone = state
+            .zones
+            .iter()
+            .find(|z| z.id.to_string().eq(id))
+            .map(|z| z.id)
+            .ok_or_else(|| VisonicErr::Backend(format!("unknown zone {}", id)))?;
+        state.troubles.retain(|t| t.zone != zone);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PanelBackend for Simulator {
+    fn panel_id(&self) -> String {
+        self.config.panel_id.to_string()
+    }
+
+    async fn status(&self) -> Result<ResStatus, VisonicErr> {
+        let mut state = self.state.lock().unwrap();
+        state.advance(Instant::now());
+
+        let partitions = state
+            .partitions
+            .iter()
+            .map(|p| Partition {
+                id: p.id,
+                state: p.state.clone(),
+                status: match p.phase {
+                    Phase::Idle => PartitionStatus::NONE,
+                    Phase::ExitDelay(_) => PartitionStatus::EXIT_DELAY,
+                    Phase::EntryDelay(_) => PartitionStatus::ENTRY_DELAY,
+                    Phase::Alarm => PartitionStatus::ALARM,
+                },
+                ready: state.open_zones(p.id).is_empty(),
+            })
+            .collect();
+
+        Ok(ResStatus {
+            connected: true,
+            partitions,
+        })
+    }
+
+    async fn set_state(&self, target: State) -> StateChange {
+        let mut state = self.state.lock().unwrap();
+        state.advance(Instant::now());
+        state.processes += 1;
+        let token = format!("sim-{}", state.processes);
+
+        let open: Vec<String> = state
+            .partitions
+            .iter()
+            .flat_map(|p| state.open_zones(p.id))
+            .filter(|z| z.kind != ZoneKind::Interior || is_away(&target))
+            .map(|z| z.location.to_string())
+            .collect();
+
+        let refusal = match target.can_set() {
+            false => Some(format!("{} can not be set", target)),
+            true if target != State::DISARM && !open.is_empty() => {
+                Some(format!("not ready, open zones: {}", open.join(", ")))
+            }
+            true => None,
+        };
+        if let Some(reason) = refusal {
+            return StateChange {
+                process_token: Some(token.to_string()),
+                process_status: Some(ResProcessStatus {
+                    token,
+                    status: "failed".to_string(),
+                    error: Some(reason.to_string()),
+                }),
+                error: Some(VisonicErr::Backend(reason)),
+            };
+        }
+
+        let exit_delay = Instant::now() + Duration::from_secs(self.config.exit_delay);
+        for i in 0..state.partitions.len() {
+            let id = state.partitions[i].id;
+            state.partitions[i].state = target.clone();
+            state.partitions[i].phase = match target {
+                State::DISARM => Phase::Idle,
+                _ => Phase::ExitDelay(exit_delay),
+            };
+            let label = if target == State::DISARM {
+                "DISARM"
+            } else {
+                "ARM"
+            };
+            state.log(label, format!("{:?}", target), None, id);
+        }
+        if target == State::DISARM {
+            state.alarms.clear();
+        }
+
+        StateChange {
+            process_token: Some(token.to_string()),
+            process_status: Some(ResProcessStatus {
+                token,
+                status: "succeeded".to_string(),
+                error: None,
+            }),
+            error: None,
+        }
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>, VisonicErr> {
+        let state = self.state.lock().unwrap();
+        let devices = state
+            .zones
+            .iter()
+            .map(|z| Device {
+                id: z.id as u64,
+                zone: Some(z.id),
+                location: Some(z.location.to_string()),
+                device_type: Some("ZONE".to_string()),
+                subtype: serde_json::to_value(z.kind)
+                    .ok()
+                    .and_then(|v| v.as_str().map(|s| s.to_string())),
+                partitions: vec![z.partition as i16],
+                bypass: z.bypass,
+                warnings: match z.open {
+                    true => vec![DeviceWarning {
+                        kind: "OPENED".to_string(),
+                    }],
+                    false => vec![],
+                },
+            })
+            .collect();
+        Ok(devices)
+    }
+
+    async fn bypass(&self, zone: u32, set: bool) -> StateChange {
+        let token = {
+            let mut state = self.state.lock().unwrap();
+            state.processes += 1;
+            format!("sim-{}", state.processes)
+        };
+        let (status, error) = match self.bypass_zone(zone, set) {
+            Ok(()) => ("succeeded", None),
+            Err(err) => ("failed", Some(err)),
+        };
+
+        StateChange {
+            process_token: Some(token.to_string()),
+            process_status: Some(ResProcessStatus {
+                token,
+                status: status.to_string(),
+                error: error.as_ref().map(|e| e.to_string()),
+            }),
+            error,
+        }
+    }
+
+    async fn events(&self) -> Result<Vec<Event>, VisonicErr> {
+        Ok(self.state.lock().unwrap().events.clone())
+    }
+
+    async fn troubles(&self) -> Result<String, VisonicErr> {
+        let state = self.state.lock().unwrap();
+        Ok(serde_json::to_string(&state.troubles).unwrap())
+    }
+
+    async fn alarms(&self) -> Result<String, VisonicErr> {
+        let mut state = self.state.lock().unwrap();
+        state.advance(Instant::now());
+        Ok(Value::Array(state.alarms.clone()).to_string())
+    }
+
+    async fn alerts(&self) -> Result<String, VisonicErr> {
+        Ok("[]".to_string())
+    }
+
+    async fn panel_info(&self) -> Result<String, VisonicErr> {
+        Ok(json!({
+            "serial": self.config.panel_id,
+            "model": self.config.model,
+            "partitions": self.config.partitions,
+        })
+        .to_string())
+    }
+
+    /// `OPEN <zone>`, `CLOSE <zone>`, `TROUBLE <zone> <type>` and `RESTORE <zone>`.
+    async fn control(&self, command: &str) -> Option<Result<(), VisonicErr>> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        match args.as_slice() {
+            ["OPEN", zone] => Some(self.set_zone(zone, true)),
+            ["CLOSE", zone] => Some(self.set_zone(zone, false)),
+            ["TROUBLE", zone, trouble_type] => Some(self.raise_trouble(zone, trouble_type)),
+            ["RESTORE", zone] => Some(self.restore(zone)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_secs(30);
+
+    fn zone(id: u32, location: &str, kind: ZoneKind) -> SimZone {
+        SimZone {
+            id,
+            location: location.to_string(),
+            partition: 1,
+            kind,
+            open: false,
+            bypass: false,
+        }
+    }
+
+    /// Disarmed panel with a door (delay), a window (perimeter), a hall (interior) and a smoke
+    /// detector (24h) zone, the tests pause time to run out the delays.
+    fn simulator() -> Simulator {
+        SimulatorConfig {
+            panel_id: "sim".to_string(),
+            exit_delay: DELAY.as_secs(),
+            entry_delay: DELAY.as_secs(),
+            partitions: vec![1],
+            model: default_model(),
+            zones: vec![
+                zone(1, "Door", ZoneKind::Delay),
+                zone(2, "Window", ZoneKind::Perimeter),
+                zone(3, "Hall", ZoneKind::Interior),
+                zone(4, "Smoke", ZoneKind::TwentyFourHour),
+            ],
+            troubles: vec![],
+        }
+        .start()
+    }
+
+    async fn partition(simulator: &Simulator) -> (State, PartitionStatus) {
+        let partition = simulator.status().await.unwrap().partitions.remove(0);
+        (partition.state, partition.status)
+    }
+
+    async fn control(simulator: &Simulator, command: &str) -> Result<(), VisonicErr> {
+        simulator.control(command).await.unwrap()
+    }
+
+    /// Arms `state` and waits for the exit delay.
+    async fn armed(state: State) -> Simulator {
+        let simulator = simulator();
+        assert!(simulator.set_state(state.clone()).await.error.is_none());
+        assert_eq!(
+            partition(&simulator).await,
+            (state.clone(), PartitionStatus::EXIT_DELAY)
+        );
+        tokio::time::advance(DELAY).await;
+        assert_eq!(partition(&simulator).await, (state, PartitionStatus::NONE));
+        simulator
+    }
+
+    #[tokio::test]
+    async fn alarms_when_the_entry_delay_runs_out() {
+        tokio::time::pause();
+        let simulator = armed(State::AWAY).await;
+
+        control(&simulator, "OPEN 1").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::ENTRY_DELAY);
+        // the interior zone follows the entry delay
+        control(&simulator, "OPEN 3").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::ENTRY_DELAY);
+
+        tokio::time::advance(DELAY).await;
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::ALARM);
+        assert_eq!(
+            simulator
+                .alarms()
+                .await
+                .unwrap()
+                .matches("BURGLARY")
+                .count(),
+            1
+        );
+
+        simulator.set_state(State::DISARM).await;
+        assert_eq!(
+            partition(&simulator).await,
+            (State::DISARM, PartitionStatus::NONE)
+        );
+        assert_eq!(simulator.alarms().await.unwrap(), "[]");
+    }
+
+    #[tokio::test]
+    async fn disarming_in_the_entry_delay_stops_the_alarm() {
+        tokio::time::pause();
+        let simulator = armed(State::AWAY).await;
+
+        control(&simulator, "OPEN 1").await.unwrap();
+        simulator.set_state(State::DISARM).await;
+        tokio::time::advance(DELAY).await;
+        assert_eq!(
+            partition(&simulator).await,
+            (State::DISARM, PartitionStatus::NONE)
+        );
+    }
+
+    #[tokio::test]
+    async fn alarms_right_away_on_perimeter_and_instant_zones() {
+        tokio::time::pause();
+        let simulator = armed(State::STAY).await;
+        // interior zones are not armed at home
+        control(&simulator, "OPEN 3").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::NONE);
+        control(&simulator, "OPEN 2").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::ALARM);
+
+        let simulator = armed(State::AWAY).await;
+        control(&simulator, "OPEN 3").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::ALARM);
+
+        let simulator = armed(State::AWAY_INSTANT).await;
+        control(&simulator, "OPEN 1").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::ALARM);
+    }
+
+    #[tokio::test]
+    async fn ignores_zones_while_disarmed_or_leaving() {
+        tokio::time::pause();
+        let simulator = simulator();
+        control(&simulator, "OPEN 2").await.unwrap();
+        control(&simulator, "CLOSE 2").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::NONE);
+
+        simulator.set_state(State::AWAY).await;
+        control(&simulator, "OPEN 1").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::EXIT_DELAY);
+
+        // the 24h zone alarms in any state
+        simulator.set_state(State::DISARM).await;
+        control(&simulator, "OPEN 4").await.unwrap();
+        assert_eq!(partition(&simulator).await.1, PartitionStatus::ALARM);
+    }
+
+    #[tokio::test]
+    async fn refuses_arming_with_open_zones() {
+        tokio::time::pause();
+        let simulator = simulator();
+        control(&simulator, "OPEN 2").await.unwrap();
+        let change = simulator.set_state(State::AWAY).await;
+        assert_eq!(
+            change.error.unwrap().to_string(),
+            VisonicErr::Backend("not ready, open zones: Window".to_string()).to_string()
+        );
+        assert_eq!(change.process_status.unwrap().status, "failed");
+        assert_eq!(
+            partition(&simulator).await,
+            (State::DISARM, PartitionStatus::NONE)
+        );
+
+        // bypassed zones and interior zones at home do not count
+        assert!(simulator.bypass(2, true).await.error.is_none());
+        control(&simulator, "OPEN 3").await.unwrap();
+        assert!(simulator.set_state(State::STAY).await.error.is_none());
+        assert!(simulator.bypass(2, false).await.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn raises_and_restores_troubles_of_known_zones() {
+        tokio::time::pause();
+        let simulator = simulator();
+        control(&simulator, "TROUBLE 2 TAMPER").await.unwrap();
+        assert!(simulator.troubles().await.unwrap().contains("TAMPER"));
+
+        control(&simulator, "RESTORE 2").await.unwrap();
+        assert_eq!(simulator.troubles().await.unwrap(), "[]");
+
+        assert!(control(&simulator, "RESTORE 9").await.is_err());
+        assert!(control(&simulator, "TROUBLE 9 TAMPER").await.is_err());
+        assert!(control(&simulator, "OPEN 9").await.is_err());
+        assert!(simulator.control("ARM").await.is_none());
+    }
+}
@@ -0,0 +1,1066 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{broadcast, watch};
+
+use crate::audit::audit_log::{AuditLog, AuditRecord};
+use crate::backend::panel_backend::PanelBackend;
+use crate::logging::logger::LogContext;
+use crate::panel::Panel;
+use crate::queue::command_queue::{Place, QueueErr};
+use crate::state::state_poller::StateCommand;
+use visonic::{
+    panel_model, Device, Partition, PartitionStatus, ResProcessStatus, State, VisonicErr,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Accepted,
+    Rejected,
+    /// The partition already is in the requested state, nothing was sent to the panel.
+    Skipped,
+}
+
+static COMMANDS: AtomicU64 = AtomicU64::new(0);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Refuses every command received from now on, from MQTT, HTTP and the scheduler alike.
+pub fn shut_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+/// Whether `shut_down` was called.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Number of commands in flight, MQTT, HTTP and scheduled ones alike.
+fn in_flight() -> &'static Mutex<watch::Sender<usize>> {
+    static IN_FLIGHT: OnceLock<Mutex<watch::Sender<usize>>> = OnceLock::new();
+    IN_FLIGHT.get_or_init(|| Mutex::new(watch::channel(0).0))
+}
+
+/// Held while a command is in flight, see `drained`.
+pub struct InFlight(());
+
+impl InFlight {
+    pub fn track() -> InFlight {
+        let count = in_flight().lock().unwrap();
+        let n = *count.borrow() + 1;
+        count.send_replace(n);
+        InFlight(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let count = in_flight().lock().unwrap();
+        let n = *count.borrow() - 1;
+        count.send_replace(n);
+    }
+}
+
+/// Completes once no command is in flight.
+pub async fn drained() {
+    let mut count = in_flight().lock().unwrap().subscribe();
+    while *count.borrow() > 0 {
+        if count.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// `[arming]` readiness check of arming commands, arming is refused while a partition is not ready.
+#[derive(Clone, Deserialize)]
+pub struct ArmingConfig {
+    /// Bypass the open zones and arm instead of refusing.
+    #[serde(default)]
+    pub force_bypass: bool,
+}
+
+/// Home Assistant alarm panel payloads, configured aliases take precedence.
+const HA_ALIASES: [(&str, &str); 3] = [
+    ("ARM_AWAY", "AWAY"),
+    ("ARM_HOME", "STAY"),
+    ("ARM_NIGHT", "NIGHT"),
+];
+
+/// Compares secrets in time depending only on their length, not on where they differ.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn default_code_arm_required() -> bool {
+    true
+}
+
+fn default_code_bypass_required() -> bool {
+    true
+}
+
+/// Failed code attempts after which commands needing the code are refused for `CODE_LOCKOUT`.
+const MAX_CODE_FAILURES: u32 = 5;
+const CODE_LOCKOUT: Duration = Duration::from_secs(60);
+
+/// Failed code attempts of a panel, shared by MQTT, HTTP and the scheduler.
+#[derive(Clone, Default)]
+pub struct CodeAttempts {
+    // consecutive failures and the end of the running lockout
+    failures: Arc<Mutex<(u32, Option<Instant>)>>,
+}
+
+impl CodeAttempts {
+    fn locked(&self) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.1 {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                *failures = (0, None);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn failed(&self) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.0 += 1;
+        if failures.0 >= MAX_CODE_FAILURES {
+            warn!(
+                "{} failed code attempts, refusing codes for {}s",
+                failures.0,
+                CODE_LOCKOUT.as_secs()
+            );
+            failures.1 = Some(Instant::now() + CODE_LOCKOUT);
+        }
+    }
+
+    fn succeeded(&self) {
+        *self.failures.lock().unwrap() = (0, None);
+    }
+}
+
+fn default_timeout() -> u64 {
+    120
+}
+
+/// `[commands]` section, payload aliases and the code expected with state commands.
+#[derive(Clone, Deserialize)]
+pub struct CommandsConfig {
+    /// Payloads mapped to gateway commands, `ARM_VACATION = "AWAY_INSTANT"`.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Code state commands have to carry, `ARM_AWAY 1234` or `{"action": "ARM_AWAY", "code": "1234"}`.
+    pub code: Option<String>,
+    /// Ask for the code when arming too, otherwise only `DISARM` needs it.
+    #[serde(default = "default_code_arm_required")]
+    pub code_arm_required: bool,
+    /// Ask for the code with `BYPASS` and `UNBYPASS`, `BYPASS 3 1234`.
+    #[serde(default = "default_code_bypass_required")]
+    pub code_bypass_required: bool,
+    /// State commands skipped while the partition already is in their state, `["AWAY", "DISARM"]`.
+    #[serde(default)]
+    pub idempotent: Vec<String>,
+    /// Seconds a command may take, waiting in the queue included, unless set in `timeouts`.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Timeouts by command, `{ DISARM = 30 }`.
+    #[serde(default)]
+    pub timeouts: HashMap<String, u64>,
+}
+
+/// Time `command` is given to wait for its turn and run.
+fn command_timeout(config: Option<&CommandsConfig>, command: &str) -> Duration {
+    let verb = command.split_whitespace().next().unwrap_or_default();
+    let secs = match config {
+        Some(config) => config
+            .timeouts
+            .iter()
+            .find(|(c, _)| c.trim().eq_ignore_ascii_case(verb))
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or(config.timeout),
+        None => default_timeout(),
+    };
+    Duration::from_secs(secs)
+}
+
+impl CommandsConfig {
+    fn is_idempotent(&self, command: &str) -> bool {
+        self.idempotent
+            .iter()
+            .any(|c| c.trim().eq_ignore_ascii_case(command))
+    }
+}
+
+/// Command parsed from a received payload, or built by the HTTP API.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommandRequest {
+    pub command: String,
+    pub code: Option<String>,
+}
+
+impl CommandRequest {
+    /// `payload` with the code masked for the audit log, only where it was parsed from: the
+    /// `code` of a JSON payload or the last word of a text one.
+    fn masked(&self, payload: &str) -> String {
+        if self.code.is_none() {
+            return payload.to_string();
+        }
+        let payload = payload.trim();
+        match serde_json::from_str::<Value>(payload) {
+            Ok(Value::Object(mut json)) if payload.starts_with('{') => {
+                json.insert("code".to_string(), Value::String("***".to_string()));
+                Value::Object(json).to_string()
+            }
+            _ => match payload.rsplit_once(char::is_whitespace) {
+                Some((command, _)) => format!("{} ***", command.trim_end()),
+                None => "***".to_string(),
+            },
+        }
+    }
+}
+
+/// Resolves aliases and splits off the code. The command word is case insensitive and
+/// whitespace is collapsed, arguments such as zone names are kept as they are. JSON payloads
+/// carry the code in `code`, text payloads as the last word sent after the command, words of
+/// an alias are never taken for a code.
+fn parse_payload(payload: &str, config: Option<&CommandsConfig>) -> CommandRequest {
+    let payload = payload.trim();
+    let json = match payload.starts_with('{') {
+        true => serde_json::from_str::<Value>(payload).ok(),
+        false => None,
+    };
+    let (text, json_code) = match &json {
+        Some(json) => (
+            json["action"].as_str().unwrap_or_default().to_string(),
+            match &json["code"] {
+                Value::String(code) => Some(code.to_string()),
+                Value::Number(code) => Some(code.to_string()),
+                _ => None,
+            },
+        ),
+        None => (payload.to_string(), None),
+    };
+
+    let mut words = text.split_whitespace();
+    let verb = words.next().unwrap_or_default().to_uppercase();
+    let alias = config
+        .and_then(|c| {
+            c.aliases
+                .iter()
+                .find(|(alias, _)| alias.eq_ignore_ascii_case(&verb))
+        })
+        .map(|(_, command)| command.trim().to_string())
+        .or_else(|| {
+            HA_ALIASES
+                .iter()
+                .find(|(alias, _)| alias.eq(&verb))
+                .map(|(_, command)| command.to_string())
+        });
+
+    let command: Vec<String> = alias
+        .unwrap_or(verb)
+        .split_whitespace()
+        .map(|w| w.to_string())
+        .collect();
+    // words of the command itself, the verb or the whole alias
+    let fixed = command.len();
+    let mut words: Vec<String> = command
+        .into_iter()
+        .chain(words.map(|w| w.to_string()))
+        .collect();
+    if let Some(verb) = words.first_mut() {
+        *verb = verb.to_uppercase();
+    }
+
+    if json.is_some() {
+        return CommandRequest {
+            command: words.join(" "),
+            code: json_code,
+        };
+    }
+
+    let bypass_code = config.is_some_and(|c| c.code.is_some() && c.code_bypass_required);
+    let bypass = matches!(
+        words.first().map(|w| w.as_str()),
+        Some("BYPASS" | "UNBYPASS")
+    );
+    let state = words.first().and_then(|verb| parse_state(verb)).is_some();
+    let has_code = match words.len() {
+        len if state => len == fixed + 1,
+        // the code follows the zone, `BYPASS Garage door 1234`
+        len if bypass && bypass_code => len > fixed.max(2),
+        _ => false,
+    };
+    CommandRequest {
+        code: if has_code { words.pop() } else { None },
+        command: words.join(" "),
+    }
+}
+
+/// Reason to refuse a state or bypass command without the configured code, repeated failures
+/// lock the code out for a while.
+fn check_code(
+    request: &CommandRequest,
+    config: Option<&CommandsConfig>,
+    attempts: &CodeAttempts,
+) -> Option<&'static str> {
+    let expected = config?.code.as_ref()?;
+    if !needs_code(&request.command, config) {
+        return None;
+    }
+    if attempts.locked() {
+        return Some("too many failed code attempts");
+    }
+    match &request.code {
+        None => Some("code required"),
+        Some(code) if !constant_time_eq(code, expected) => {
+            attempts.failed();
+            Some("invalid code")
+        }
+        Some(_) => {
+            attempts.succeeded();
+            None
+        }
+    }
+}
+
+/// What a command acts on, only state commands are echoed to the status topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandKind {
+    State,
+    Zone,
+    Control,
+}
+
+#[derive(Debug)]
+pub struct CommandOutcome {
+    pub command: String,
+    pub kind: CommandKind,
+    pub decision: Decision,
+    pub reason: Option<String>,
+    pub process_token: Option<String>,
+    pub process_status: Option<ResProcessStatus>,
+    pub error: Option<String>,
+    /// Bypassable zones after a zone command, published to the zones topic.
+    pub zones: Option<Vec<Device>>,
+    /// Zones an arming command was refused for.
+    pub open_zones: Option<Vec<Device>>,
+}
+
+impl CommandOutcome {
+    fn accepted(command: String, kind: CommandKind) -> CommandOutcome {
+        CommandOutcome {
+            command,
+            kind,
+            decision: Decision::Accepted,
+            reason: None,
+            process_token: None,
+            process_status: None,
+            error: None,
+            zones: None,
+            open_zones: None,
+        }
+    }
+
+    fn rejected(command: String, kind: CommandKind, reason: &str) -> CommandOutcome {
+        CommandOutcome {
+            decision: Decision::Rejected,
+            reason: Some(reason.to_string()),
+            ..CommandOutcome::accepted(command, kind)
+        }
+    }
+
+    fn skipped(command: String, reason: String) -> CommandOutcome {
+        CommandOutcome {
+            decision: Decision::Skipped,
+            reason: Some(reason),
+            ..CommandOutcome::accepted(command, CommandKind::State)
+        }
+    }
+
+    /// Payload published back to the status topic, nothing for rejected commands except
+    /// `NOT_READY` for refused arming. Skipped commands are echoed like accepted ones.
+    pub fn reply(&self) -> Option<String> {
+        match self.decision {
+            Decision::Skipped => Some(self.command.to_string()),
+            Decision::Rejected if self.open_zones.is_some() => Some("NOT_READY".to_string()),
+            Decision::Rejected => None,
+            Decision::Accepted if self.error.is_some() => Some("ERROR".to_string()),
+            Decision::Accepted if self.kind == CommandKind::State => Some(self.command.to_string()),
+            Decision::Accepted => None,
+        }
+    }
+}
+
+fn parse_state(command: &str) -> Option<State> {
+    match command {
+        "AWAY" => Some(State::AWAY),
+        "DISARM" => Some(State::DISARM),
+        "NIGHT" => Some(State::NIGHT),
+        "STAY" => Some(State::STAY),
+        "AWAY_INSTANT" => Some(State::AWAY_INSTANT),
+        "STAY_INSTANT" => Some(State::STAY_INSTANT),
+        "NIGHT_INSTANT" => Some(State::NIGHT_INSTANT),
+        "LATCHKEY" => Some(State::LATCHKEY),
+        _ => None,
+    }
+}
+
+/// Whether `command` has to carry the configured code.
+pub fn needs_code(command: &str, config: Option<&CommandsConfig>) -> bool {
+    let config = match config {
+        Some(config) if config.code.is_some() => config,
+        _ => return false,
+    };
+    match parse_state(command) {
+        Some(State::DISARM) => true,
+        Some(_) => config.code_arm_required,
+        None => parse_bypass(command).is_some() && config.code_bypass_required,
+    }
+}
+
+/// Gateway command `payload` resolves to through the aliases of `config`, and its kind.
+pub fn resolve(payload: &str, config: Option<&CommandsConfig>) -> (String, CommandKind) {
+    let command = parse_payload(payload, config).command;
+    let kind = command_kind(&command);
+    (command, kind)
+}
+
+fn command_kind(command: &str) -> CommandKind {
+    match (
+        parse_state(command),
+        command.eq("ZONES") || parse_bypass(command).is_some(),
+    ) {
+        (Some(_), _) => CommandKind::State,
+        (None, true) => CommandKind::Zone,
+        (None, false) => CommandKind::Control,
+    }
+}
+
+/// `BYPASS <zone>` and `UNBYPASS <zone>`, the zone is a number or a location name.
+fn parse_bypass(command: &str) -> Option<(bool, &str)> {
+    match command.split_once(' ') {
+        Some(("BYPASS", target)) => Some((true, target.trim())),
+        Some(("UNBYPASS", target)) => Some((false, target.trim())),
+        _ => None,
+    }
+}
+
+/// Zones of the panel that can be bypassed.
+pub async fn bypassable_zones(backend: &dyn PanelBackend) -> Result<Vec<Device>, VisonicErr> {
+    let devices = backend.devices().await?;
+    Ok(devices.into_iter().filter(|d| d.is_zone()).collect())
+}
+
+async fn dispatch_zones(command: String, backend: &dyn PanelBackend) -> CommandOutcome {
+    let mut outcome = CommandOutcome::accepted(command, CommandKind::Zone);
+    match bypassable_zones(backend).await {
+        Ok(zones) => outcome.zones = Some(zones),
+        Err(err) => {
+            error!("Failure {}: {}", outcome.command, err);
+            outcome.error = Some(err.to_string());
+        }
+    }
+    outcome
+}
+
+async fn dispatch_bypass(
+    command: String,
+    set: bool,
+    target: &str,
+    backend: &dyn PanelBackend,
+) -> CommandOutcome {
+    let zones = match bypassable_zones(backend).await {
+        Ok(zones) => zones,
+        Err(err) => {
+            error!("Failure {}: {}", command, err);
+            let mut outcome = CommandOutcome::accepted(command, CommandKind::Zone);
+            outcome.error = Some(err.to_string());
+            return outcome;
+        }
+    };
+    let zone = match zones
+        .iter()
+        .find(|d| d.matches(target))
+        .and_then(|d| d.zone)
+    {
+        Some(zone) => zone,
+        None => {
+            info!("unknown zone in command: {}", command);
+            return CommandOutcome::rejected(command, CommandKind::Zone, "unknown zone");
+        }
+    };
+
+    let change = backend.bypass(zone, set).await;
+    if let Some(err) = &change.error {
+        error!("Failure {}: {}", command, err);
+    }
+
+    let mut outcome = CommandOutcome::accepted(command, CommandKind::Zone);
+    outcome.process_token = change.process_token;
+    outcome.process_status = change.process_status;
+    outcome.error = change.error.map(|err| err.to_string());
+    outcome.zones = bypassable_zones(backend).await.ok();
+    outcome
+}
+
+/// Panel model not supporting `state`, instant and latchkey modes depend on it. Panels not
+/// reporting their model are let through, a failure to read it refuses the command like the
+/// client does.
+async fn unsupported_by(
+    state: &State,
+    backend: &dyn PanelBackend,
+) -> Result<Option<String>, VisonicErr> {
+    // only instant and latchkey modes depend on the panel model
+    if state.supported_by("") {
+        return Ok(None);
+    }
+    let panel_info = backend.panel_info().await?;
+    Ok(panel_model(&panel_info).filter(|model| !state.supported_by(model)))
+}
+
+/// Whether the partitions `backend` acts on already are in `state`. Never while a partition
+/// is in alarm or in exit or entry delay, a `DISARM` has to reach the panel then.
+async fn already_in(state: &State, backend: &dyn PanelBackend) -> Result<bool, VisonicErr> {
+    let status = backend.status().await?;
+    let partition = backend.partition();
+    let mut partitions = status
+        .partitions
+        .iter()
+        .filter(|p| partition.is_none() || partition == Some(p.id))
+        .peekable();
+    let settled = |p: &Partition| {
+        !matches!(
+            p.status,
+            PartitionStatus::ALARM | PartitionStatus::ENTRY_DELAY | PartitionStatus::EXIT_DELAY
+        ) && p.state != State::ALARM
+    };
+    Ok(partitions.peek().is_some() && partitions.all(|p| settled(p) && p.state.eq(state)))
+}
+
+/// Faulted zones of partitions that are not ready, nothing when every partition is ready.
+async fn not_ready_zones(backend: &dyn PanelBackend) -> Result<Option<Vec<Device>>, VisonicErr> {
+    let status = backend.status().await?;
+    let not_ready: Vec<i16> = status
+        .partitions
+        .iter()
+        .filter(|p| !p.ready)
+        .map(|p| p.id as i16)
+        .collect();
+    if not_ready.is_empty() {
+        return Ok(None);
+    }
+
+    let zones = bypassable_zones(backend)
+        .await?
+        .into_iter()
+        .filter(|d| !d.bypass && d.is_faulted())
+        .filter(|d| d.partitions.is_empty() || d.partitions.iter().any(|p| not_ready.contains(p)))
+        .collect();
+    Ok(Some(zones))
+}
+
+/// Checks readiness before arming, bypasses the open zones in force mode and returns them.
+/// `Err` is the outcome of the refused or failed command, nothing is left bypassed then.
+async fn prepare_arming(
+    command: &str,
+    arming: &ArmingConfig,
+    backend: &dyn PanelBackend,
+) -> Result<Vec<(String, u32)>, CommandOutcome> {
+    let failed = |err: String| {
+        error!("Failure {}: {}", command, err);
+        let mut outcome = CommandOutcome::accepted(command.to_string(), CommandKind::State);
+        outcome.error = Some(err);
+        outcome
+    };
+
+    let zones = match not_ready_zones(backend).await {
+        Ok(None) => return Ok(vec![]),
+        Ok(Some(zones)) => zones,
+        Err(err) => return Err(failed(err.to_string())),
+    };
+
+    let labels: Vec<String> = zones.iter().map(|d| d.label()).collect();
+    let unnumbered = zones.iter().find(|d| d.zone.is_none());
+    let reason = if zones.is_empty() {
+        // nothing to bypass would make the panel ready
+        Some("not ready, no open zones reported".to_string())
+    } else if !arming.force_bypass {
+        Some(format!("not ready, open zones: {}", labels.join(", ")))
+    } else {
+        unnumbered.map(|zone| format!("not ready, zone {} has no number to bypass", zone.label()))
+    };
+    if let Some(reason) = reason {
+        info!("Refusing {}: {}", command, reason);
+        let mut outcome =
+            CommandOutcome::rejected(command.to_string(), CommandKind::State, &reason);
+        outcome.open_zones = Some(zones);
+        return Err(outcome);
+    }
+
+    let mut bypassed = vec![];
+    for (zone, number) in zones.iter().filter_map(|d| d.zone.map(|n| (d, n))) {
+        let change = backend.bypass(number, true).await;
+        if let Some(err) = change.error {
+            restore_bypassed(command, &bypassed, backend).await;
+            return Err(failed(format!(
+                "bypass of zone {} failed: {}",
+                zone.label(),
+                err
+            )));
+        }
+        info!("Bypassed zone {} before {}", zone.label(), command);
+        bypassed.push((zone.label(), number));
+    }
+    Ok(bypassed)
+}
+
+/// Restores zones bypassed by `prepare_arming` when the panel did not arm.
+async fn restore_bypassed(command: &str, zones: &[(String, u32)], backend: &dyn PanelBackend) {
+    for (label, zone) in zones {
+        match backend.bypass(*zone, false).await.error {
+            Some(err) => error!(
+                "Failed to restore zone {} after {}: {}",
+                label, command, err
+            ),
+            None => info!("Restored zone {} after {}", label, command),
+        }
+    }
+}
+
+async fn dispatch_control(command: String, backend: &dyn PanelBackend) -> CommandOutcome {
+    match backend.control(&command).await {
+        Some(result) => {
+            if let Err(err) = &result {
+                error!("Failure {}: {}", command, err);
+            }
+            let mut outcome = CommandOutcome::accepted(command, CommandKind::Control);
+            outcome.error = result.err().map(|err| err.to_string());
+            outcome
+        }
+        None => {
+            info!("unknown mqtt command: {}", command);
+            CommandOutcome::rejected(command, CommandKind::Control, "unknown command")
+        }
+    }
+}
+
+pub async fn dispatch_command(command: String, panel: &Panel) -> CommandOutcome {
+    let backend = panel.backend.as_ref();
+    if command.eq("ZONES") {
+        return dispatch_zones(command, backend).await;
+    }
+    if let Some((set, target)) = parse_bypass(&command) {
+        let target = target.to_string();
+        return dispatch_bypass(command, set, &target, backend).await;
+    }
+    let state = match parse_state(&command) {
+        Some(state) => state,
+        None => return dispatch_control(command, backend).await,
+    };
+
+    match unsupported_by(&state, backend).await {
+        Ok(None) => (),
+        Ok(Some(model)) => {
+            let reason = format!("{} is not supported by panel model {}", command, model);
+            info!("Refusing {}: {}", command, reason);
+            return CommandOutcome::rejected(command, CommandKind::State, &reason);
+        }
+        Err(err) => {
+            error!("Failure {}: failed to read panel model: {}", command, err);
+            let mut outcome = CommandOutcome::accepted(command, CommandKind::State);
+            outcome.error = Some(format!("failed to read panel model: {}", err));
+            return outcome;
+        }
+    }
+
+    if panel
+        .commands
+        .as_ref()
+        .is_some_and(|c| c.is_idempotent(&command))
+    {
+        match already_in(&state, backend).await {
+            Ok(true) => {
+                let reason = format!("already in state {}", state);
+                info!("Skipping {}: {}", command, reason);
+                return CommandOutcome::skipped(command, reason);
+            }
+            Ok(false) => (),
+            Err(err) => error!("Failed to read state before {}: {}", command, err),
+        }
+    }
+
+    let bypassed = match (&panel.arming, state != State::DISARM) {
+        (Some(arming), true) => match prepare_arming(&command, arming, backend).await {
+            Ok(bypassed) => bypassed,
+            Err(outcome) => return outcome,
+        },
+        _ => vec![],
+    };
+
+    let change = backend.set_state(state).await;
+    if let Some(err) = &change.error {
+        error!("Failure {}: {}", command, err);
+        restore_bypassed(&command, &bypassed, backend).await;
+    }
+
+    let mut outcome = CommandOutcome::accepted(command, CommandKind::State);
+    outcome.process_token = change.process_token;
+    outcome.process_status = change.process_status;
+    outcome.error = change.error.map(|err| err.to_string());
+    outcome
+}
+
+/// Command received from `source`, holding its place in the panel's queue.
+pub struct Received {
+    started: Instant,
+    timestamp: DateTime<Utc>,
+    command_id: String,
+    source: String,
+    payload: String,
+    command: String,
+    // place in the queue, or why the command was refused
+    place: Result<Place, &'static str>,
+    // the state poller was told a state command started
+    notice: Option<StateNotice>,
+    in_flight: InFlight,
+}
+
+/// Ends a started state command for the state poller, as failed unless finished.
+struct StateNotice {
+    commands: broadcast::Sender<StateCommand>,
+    finished: bool,
+}
+
+impl StateNotice {
+    fn finish(mut self, command: StateCommand) {
+        self.finished = true;
+        // nobody listens without [state] section
+        let _ = self.commands.send(command);
+    }
+}
+
+impl Drop for StateNotice {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.commands.send(StateCommand::Failed);
+        }
+    }
+}
+
+/// Parses `payload` received from `source` and queues it right away, so commands of a panel
+/// run in the order they arrived. The state poller is told about a queued state command
+/// here and about its end once it was executed or dropped.
+pub fn receive(panel: &Panel, source: String, payload: String) -> Received {
+    let request = parse_payload(&payload, panel.commands.as_ref());
+    let payload = request.masked(&payload);
+    receive_request(panel, source, payload, request)
+}
+
+/// Queues a `request` that needs no parsing, `payload` is recorded to the audit log and must
+/// not contain the code.
+pub fn receive_request(
+    panel: &Panel,
+    source: String,
+    payload: String,
+    request: CommandRequest,
+) -> Received {
+    let in_flight = InFlight::track();
+    let started = Instant::now();
+    let timestamp = Utc::now();
+    let command_id = format!("cmd-{}", COMMANDS.fetch_add(1, Ordering::SeqCst) + 1);
+
+    let place = match check_code(&request, panel.commands.as_ref(), &panel.code_attempts) {
+        _ if is_shutting_down() => Err("shutting down"),
+        Some(reason) => Err(reason),
+        None => {
+            let state = command_kind(&request.command) == CommandKind::State;
+            Ok(panel.queue.enqueue(&request.command, state))
+        }
+    };
+
+    let notice = match (&place, parse_state(&request.command)) {
+        (Ok(_), Some(state)) => {
+            panel.state_command(StateCommand::Started(state));
+            Some(StateNotice {
+                commands: panel.state_commands.clone(),
+                finished: false,
+            })
+        }
+        _ => None,
+    };
+
+    Received {
+        started,
+        timestamp,
+        command_id,
+        source,
+        payload,
+        command: request.command,
+        place,
+        notice,
+        in_flight,
+    }
+}
+
+/// Dispatches a received command once its turn comes and records it to the audit log.
+pub async fn execute(
+    panel: &Panel,
+    received: Received,
+    audit: Option<&AuditLog>,
+) -> CommandOutcome {
+    let context = LogContext::panel(&panel.name).command(&received.command_id);
+    context
+        .scope(execute_received(panel, received, audit))
+        .await
+}
+
+async fn execute_received(
+    panel: &Panel,
+    received: Received,
+    audit: Option<&AuditLog>,
+) -> CommandOutcome {
+    let Received {
+        started,
+        timestamp,
+        command_id,
+        source,
+        payload,
+        command,
+        place,
+        notice,
+        // dropped once the command is recorded
+        in_flight: _in_flight,
+    } = received;
+
+    let kind = command_kind(&command);
+    let outcome = match place {
+        Err(reason) => {
+            info!("Refusing {}: {}", command, reason);
+            CommandOutcome::rejected(command, kind, reason)
+        }
+        Ok(place) => {
+            let timeout = command_timeout(panel.commands.as_ref(), &command);
+            match place
+                .run(timeout, dispatch_command(command.to_string(), panel))
+                .await
+            {
+                Ok(outcome) => outcome,
+                Err(err @ QueueErr::Superseded(_)) => {
+                    info!("Dropping {}: {}", command, err);
+                    CommandOutcome::rejected(command, kind, &err.to_string())
+                }
+                Err(err @ QueueErr::TimedOut(_)) => {
+                    error!("Failure {}: {}", command, err);
+                    let mut outcome = CommandOutcome::accepted(command, kind);
+                    outcome.error = Some(err.to_string());
+                    outcome
+                }
+            }
+        }
+    };
+
+    if let Some(notice) = notice {
+        notice.finish(match (&outcome.decision, &outcome.error) {
+            (Decision::Accepted, None) | (Decision::Skipped, _) => StateCommand::Completed,
+            _ => StateCommand::Failed,
+        });
+    }
+
+    if let Some(audit) = audit {
+        let record = AuditRecord {
+            timestamp,
+            command_id: Some(command_id),
+            panel: panel.name.to_string(),
+            source,
+            payload,
+            decision: outcome.decision,
+            reason: outcome.reason.clone(),
+            process_token: outcome.process_token.clone(),
+            process_status: outcome.process_status.clone(),
+            error: outcome.error.clone(),
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        if let Err(err) = audit.append(&record) {
+            error!("Failed to write audit log: {}", err);
+        }
+    }
+
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CommandsConfig {
+        CommandsConfig {
+            aliases: HashMap::from([
+                ("ARM_VACATION".to_string(), "AWAY_INSTANT".to_string()),
+                ("GARAGE_OPEN".to_string(), "BYPASS Garage door".to_string()),
+            ]),
+            code: Some("1234".to_string()),
+            code_arm_required: true,
+            code_bypass_required: true,
+            idempotent: vec![],
+            timeout: default_timeout(),
+            timeouts: HashMap::new(),
+        }
+    }
+
+    fn request(command: &str, code: Option<&str>) -> CommandRequest {
+        CommandRequest {
+            command: command.to_string(),
+            code: code.map(|c| c.to_string()),
+        }
+    }
+
+    fn parse(payload: &str) -> CommandRequest {
+        parse_payload(payload, Some(&config()))
+    }
+
+    #[test]
+    fn parses_text_payloads() {
+        assert_eq!(parse(" disarm  1234 "), request("DISARM", Some("1234")));
+        assert_eq!(parse("AWAY"), request("AWAY", None));
+        assert_eq!(parse("arm_away 1234"), request("AWAY", Some("1234")));
+        assert_eq!(parse("ZONES"), request("ZONES", None));
+        assert_eq!(parse("OPEN 3"), request("OPEN 3", None));
+        assert_eq!(
+            parse("bypass Garage door 1234"),
+            request("BYPASS Garage door", Some("1234"))
+        );
+        assert_eq!(parse("BYPASS 3"), request("BYPASS 3", None));
+        assert_eq!(
+            parse_payload("BYPASS Garage door", None),
+            request("BYPASS Garage door", None)
+        );
+    }
+
+    #[test]
+    fn never_takes_alias_words_for_a_code() {
+        assert_eq!(
+            parse("arm_vacation 1234"),
+            request("AWAY_INSTANT", Some("1234"))
+        );
+        assert_eq!(parse("GARAGE_OPEN"), request("BYPASS Garage door", None));
+        assert_eq!(
+            parse("garage_open 1234"),
+            request("BYPASS Garage door", Some("1234"))
+        );
+    }
+
+    #[test]
+    fn takes_the_code_of_json_payloads() {
+        assert_eq!(
+            parse(r#"{"action": "ARM_AWAY", "code": 1234}"#),
+            request("AWAY", Some("1234"))
+        );
+        assert_eq!(
+            parse(r#"{"action": "BYPASS Garage door", "code": "1234"}"#),
+            request("BYPASS Garage door", Some("1234"))
+        );
+        assert_eq!(
+            parse(r#"{"action": "BYPASS Garage door"}"#),
+            request("BYPASS Garage door", None)
+        );
+        assert_eq!(parse(r#"{"action": "DISARM"}"#), request("DISARM", None));
+    }
+
+    #[test]
+    fn masks_only_the_parsed_code() {
+        let masked = |payload: &str| parse(payload).masked(payload);
+        assert_eq!(masked("DISARM 1234"), "DISARM ***");
+        assert_eq!(masked("BYPASS 1234 1234"), "BYPASS 1234 ***");
+        assert_eq!(masked("AWAY"), "AWAY");
+        let json: Value =
+            serde_json::from_str(&masked(r#"{"action": "BYPASS 1234", "code": "1234"}"#)).unwrap();
+        assert_eq!(json["action"], "BYPASS 1234");
+        assert_eq!(json["code"], "***");
+    }
+
+    #[test]
+    fn compares_codes() {
+        assert!(constant_time_eq("1234", "1234"));
+        assert!(!constant_time_eq("1234", "1235"));
+        assert!(!constant_time_eq("1234", "12345"));
+        assert!(!constant_time_eq("", "1234"));
+    }
+
+    #[test]
+    fn requires_the_code_where_configured() {
+        let (config, attempts) = (config(), CodeAttempts::default());
+        let check = |r: &CommandRequest| check_code(r, Some(&config), &attempts);
+
+        assert_eq!(check(&request("DISARM", None)), Some("code required"));
+        assert_eq!(check(&request("DISARM", Some("1234"))), None);
+        assert_eq!(
+            check(&request("BYPASS 3", Some("0000"))),
+            Some("invalid code")
+        );
+        assert_eq!(check(&request("ZONES", None)), None);
+        assert_eq!(check(&request("OPEN 3", None)), None);
+
+        let arming = CommandsConfig {
+            code_arm_required: false,
+            code_bypass_required: false,
+            ..config.clone()
+        };
+        assert_eq!(
+            check_code(&request("AWAY", None), Some(&arming), &attempts),
+            None
+        );
+        assert_eq!(
+            check_code(&request("BYPASS 3", None), Some(&arming), &attempts),
+            None
+        );
+        assert_eq!(check_code(&request("DISARM", None), None, &attempts), None);
+    }
+
+    #[test]
+    fn locks_out_repeated_failures() {
+        let (config, attempts) = (config(), CodeAttempts::default());
+        let check = |code| check_code(&request("DISARM", Some(code)), Some(&config), &attempts);
+
+        // a valid code resets the count
+        for _ in 0..MAX_CODE_FAILURES - 1 {
+            assert_eq!(check("0000"), Some("invalid code"));
+        }
+        assert_eq!(check("1234"), None);
+
+        for _ in 0..MAX_CODE_FAILURES {
+            assert_eq!(check("0000"), Some("invalid code"));
+        }
+        assert_eq!(check("1234"), Some("too many failed code attempts"));
+        // commands without code are not locked out
+        assert_eq!(
+            check_code(&request("ZONES", None), Some(&config), &attempts),
+            None
+        );
+
+        attempts.failures.lock().unwrap().1 = Some(Instant::now());
+        assert_eq!(check("1234"), None);
+    }
+
+    #[tokio::test]
+    async fn drains_commands_in_flight() {
+        let in_flight = InFlight::track();
+        let wait = Duration::from_millis(50);
+        assert!(tokio::time::timeout(wait, drained()).await.is_err());
+
+        drop(in_flight);
+        tokio::time::timeout(wait, drained()).await.unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::backend::panel_backend::PanelBackend;
+use crate::mqtt::mqtt_handler::MqttPublisher;
+
+const PANEL_ONLINE: &str = "ONLINE";
+const PANEL_OFFLINE: &str = "OFFLINE";
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_cooldown() -> u64 {
+    3600
+}
+
+/// `[connectivity]` watchdog of the panel connection to the cloud.
+#[derive(Clone, Deserialize)]
+pub struct ConnectivityConfig {
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Seconds the panel has to stay disconnected before the wakeup SMS is requested,
+    /// no wakeup without it.
+    pub wakeup_after: Option<u64>,
+    /// Minimum seconds between two wakeup SMS.
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+    /// Wakeup SMS (phone number and text) is published here for an SMS gateway to send.
+    pub sms_topic: Option<String>,
+}
+
+impl ConnectivityConfig {
+    /// Publishes `ONLINE`/`OFFLINE` to `availability_topic` on changes and requests the wakeup
+    /// SMS while the panel stays disconnected.
+    pub async fn watch(
+        &self,
+        backend: Arc<dyn PanelBackend>,
+        publisher: MqttPublisher,
+        availability_topic: Option<String>,
+    ) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.interval));
+        let mut published: Option<bool> = None;
+        let mut disconnected_since: Option<Instant> = None;
+        let mut last_wakeup: Option<Instant> = None;
+
+        loop {
+            interval.tick().await;
+
+            let connected = match backend.status().await {
+                Ok(status) => status.connected,
+                Err(err) => {
+                    error!("Failed to check panel connectivity: {}", err);
+                    continue;
+                }
+            };
+
+            if published != Some(connected) {
+                match connected {
+                    true => info!("Panel is connected"),
+                    false => warn!("Panel is disconnected"),
+                }
+                let payload = if connected {
+                    PANEL_ONLINE
+                } else {
+                    PANEL_OFFLINE
+                };
+                match &availability_topic {
+                    Some(topic) => match publisher
+                        .publish(topic.to_string(), payload.to_string())
+                        .await
+                    {
+                        Ok(_) => published = Some(connected),
+                        Err(err) => error!("Error publishing availability: {}", err),
+                    },
+                    None => published = Some(connected),
+                }
+            }
+
+            let now = Instant::now();
+            let since = match (connected, disconnected_since) {
+                (true, _) => {
+                    disconnected_since = None;
+                    continue;
+                }
+                (false, None) => *disconnected_since.insert(now),
+                (false, Some(since)) => since,
+            };
+
+            let wakeup_after = match self.wakeup_after {
+                Some(wakeup_after) => Duration::from_secs(wakeup_after),
+                None => continue,
+            };
+            let cooled_down = last_wakeup.map_or(true, |last| {
+                now.duration_since(last) >= Duration::from_secs(self.cooldown)
+            });
+            if now.duration_since(since) < wakeup_after || !cooled_down {
+                continue;
+            }
+
+            last_wakeup = Some(now);
+            self.wakeup(backend.as_ref(), &publisher).await;
+        }
+    }
+
+    async fn wakeup(&self, backend: &dyn PanelBackend, publisher: &MqttPublisher) {
+        let sms = match backend.wakeup_sms().await {
+            Ok(Some(sms)) => sms,
+            Ok(None) => {
+                warn!("Panel stays disconnected and has no wakeup SMS");
+                return;
+            }
+            Err(err) => {
+                error!("Failed to request wakeup SMS: {}", err);
+                return;
+            }
+        };
+
+        info!("Panel stays disconnected, requested wakeup SMS");
+        if let Some(topic) = &self.sms_topic {
+            if let Err(err) = publisher.publish_transient(topic.to_string(), sms).await {
+                error!("Error publishing wakeup SMS: {}", err);
+            }
+        }
+    }
+}
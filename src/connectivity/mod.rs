@@ -0,0 +1 @@
+pub mod connectivity_watchdog;
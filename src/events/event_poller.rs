@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info};
+use serde::Deserialize;
+
+use crate::backend::panel_backend::PanelBackend;
+use crate::events::event_store::EventStore;
+use crate::mqtt::mqtt_handler::MqttPublisher;
+use visonic::{Event, VisonicErr};
+
+#[derive(Clone, Deserialize)]
+pub struct EventsConfig {
+    pub topic: String,
+    pub store: PathBuf,
+    pub interval: u64,
+}
+
+async fn fetch_events(backend: &dyn PanelBackend) -> Result<Vec<Event>, VisonicErr> {
+    let mut events = backend.events().await?;
+    events.sort_by_key(|e| e.event);
+    Ok(events)
+}
+
+impl EventsConfig {
+    pub async fn poll(
+        &self,
+        backend: Arc<dyn PanelBackend>,
+        publisher: MqttPublisher,
+    ) -> std::io::Result<()> {
+        let mut store = EventStore::open(self.store.clone())?;
+        let mut interval = tokio::time::interval(Duration::from_secs(self.interval));
+
+        loop {
+            interval.tick().await;
+
+            let events = match fetch_events(backend.as_ref()).await {
+                Ok(events) => events,
+                Err(err) => {
+                    error!("Failed to fetch visonic events: {}", err);
+                    continue;
+                }
+            };
+
+            // on a fresh store only record history, so the whole panel log is not replayed
+            if !store.is_seeded() {
+                match store.seed(&events) {
+                    Ok(seeded) => info!("Seeded event store with {} existing events", seeded),
+                    Err(err) => error!("Failed to seed event store: {}", err),
+                }
+                continue;
+            }
+
+            let new_events: Vec<Event> = events.into_iter().filter(|e| store.is_new(e)).collect();
+            for event in new_events.iter() {
+                let payload = serde_json::to_string(event)?;
+                if let Err(err) = publisher
+                    .publish_transient(self.topic.to_string(), payload)
+                    .await
+                {
+                    error!("Error publishing event {} to mqtt: {}", event.event, err);
+                    continue;
+                }
+                if let Err(err) = store.append(event) {
+                    error!("Failed to record event {}: {}", event.event, err);
+                }
+            }
+        }
+    }
+}
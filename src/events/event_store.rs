@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use log::warn;
+
+use visonic::Event;
+
+/// Append-only JSON lines history of events already seen on the panel. The file exists once the
+/// panel log was read, even when it had no events.
+pub struct EventStore {
+    path: PathBuf,
+    seen: HashSet<u64>,
+    seeded: bool,
+}
+
+impl EventStore {
+    pub fn open(path: PathBuf) -> std::io::Result<EventStore> {
+        let mut seen = HashSet::new();
+        let seeded = path.exists();
+
+        if seeded {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                match serde_json::from_str::<Event>(&line) {
+                    Ok(event) => {
+                        seen.insert(event.event);
+                    }
+                    Err(err) => warn!("Skipping malformed event store line: {}", err),
+                }
+            }
+        }
+
+        Ok(EventStore { path, seen, seeded })
+    }
+
+    /// Whether the panel log was recorded before, new events are published only then.
+    pub fn is_seeded(&self) -> bool {
+        self.seeded
+    }
+
+    pub fn is_new(&self, event: &Event) -> bool {
+        !self.seen.contains(&event.event)
+    }
+
+    /// Records `events` as history without publishing them, so the whole panel log is not
+    /// replayed on a fresh store. Returns how many were recorded.
+    pub fn seed(&mut self, events: &[Event]) -> std::io::Result<usize> {
+        let mut file = self.file()?;
+        let mut seeded = 0;
+        for event in events {
+            if self.seen.insert(event.event) {
+                writeln!(file, "{}", serde_json::to_string(event)?)?;
+                seeded += 1;
+            }
+        }
+        self.seeded = true;
+        Ok(seeded)
+    }
+
+    /// Records `event` as seen, also when writing it fails so it is not published again.
+    pub fn append(&mut self, event: &Event) -> std::io::Result<()> {
+        self.seen.insert(event.event);
+        let line = serde_json::to_string(event)?;
+        writeln!(self.file()?, "{}", line)
+    }
+
+    fn file(&self) -> std::io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static STORES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Path of a store that does not exist yet.
+    fn path() -> PathBuf {
+        let n = STORES.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("events-{}-{}.jsonl", std::process::id(), n));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn event(id: u64) -> Event {
+        Event {
+            event: id,
+            type_id: Some(1),
+            label: Some("ARM".to_string()),
+            description: None,
+            appointment: None,
+            datetime: None,
+            device_type: None,
+            zone: None,
+            partitions: vec![],
+        }
+    }
+
+    #[test]
+    fn seeds_a_fresh_store_once() {
+        let path = path();
+        let mut store = EventStore::open(path.clone()).unwrap();
+        assert!(!store.is_seeded());
+
+        assert_eq!(store.seed(&[event(1), event(2), event(1)]).unwrap(), 2);
+        assert!(store.is_seeded());
+        assert!(!store.is_new(&event(2)));
+        assert!(store.is_new(&event(3)));
+
+        let store = EventStore::open(path.clone()).unwrap();
+        assert!(store.is_seeded());
+        assert!(!store.is_new(&event(1)));
+        assert!(store.is_new(&event(3)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stays_seeded_on_an_empty_panel_log() {
+        let path = path();
+        let mut store = EventStore::open(path.clone()).unwrap();
+        assert_eq!(store.seed(&[]).unwrap(), 0);
+
+        // the first event after an empty log is new, not history
+        let store = EventStore::open(path.clone()).unwrap();
+        assert!(store.is_seeded());
+        assert!(store.is_new(&event(1)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reopens_appended_events_and_skips_malformed_lines() {
+        let path = path();
+        let mut store = EventStore::open(path.clone()).unwrap();
+        store.seed(&[]).unwrap();
+        store.append(&event(7)).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "not json"))
+            .unwrap();
+        store.append(&event(8)).unwrap();
+
+        let store = EventStore::open(path.clone()).unwrap();
+        assert!(!store.is_new(&event(7)));
+        assert!(!store.is_new(&event(8)));
+        assert!(store.is_new(&event(9)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_events_seen_when_writing_fails() {
+        // a directory can not be opened for appending
+        let path = std::env::temp_dir();
+        let mut store = EventStore {
+            path,
+            seen: HashSet::new(),
+            seeded: true,
+        };
+        assert!(store.append(&event(1)).is_err());
+        assert!(!store.is_new(&event(1)));
+    }
+}
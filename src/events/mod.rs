@@ -0,0 +1,2 @@
+pub mod event_poller;
+pub mod event_store;
@@ -0,0 +1,238 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::logging::logger::LogContext;
+use crate::panel::Panel;
+
+fn default_interval() -> u64 {
+    60
+}
+
+fn default_max_age() -> u64 {
+    300
+}
+
+/// `[health]` endpoint and systemd integration.
+#[derive(Clone, Deserialize)]
+pub struct HealthConfig {
+    pub bind: SocketAddr,
+    /// Seconds between panel status probes.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Panel is unhealthy when its last successful call is older than this many seconds.
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+    /// Send READY and WATCHDOG notifications to systemd.
+    #[serde(default)]
+    pub systemd: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PanelHealth {
+    pub name: String,
+    /// Panel connection to the cloud as last reported, unknown before the first probe.
+    pub connected: Option<bool>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub healthy: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub mqtt_connected: bool,
+    pub panels: Vec<PanelHealth>,
+}
+
+#[derive(Clone)]
+struct Health {
+    mqtt: watch::Receiver<bool>,
+    panels: Arc<Mutex<Vec<PanelHealth>>>,
+    max_age: chrono::Duration,
+}
+
+impl Health {
+    fn report(&self) -> HealthReport {
+        let now = Utc::now();
+        let panels: Vec<PanelHealth> = self
+            .panels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| PanelHealth {
+                healthy: p.last_success.is_some_and(|t| now - t <= self.max_age),
+                ..p.clone()
+            })
+            .collect();
+        let mqtt_connected = *self.mqtt.borrow();
+
+        HealthReport {
+            healthy: mqtt_connected && panels.iter().all(|p| p.healthy),
+            mqtt_connected,
+            panels,
+        }
+    }
+
+    fn succeeded(&self, name: &str, connected: bool) {
+        let mut panels = self.panels.lock().unwrap();
+        if let Some(panel) = panels.iter_mut().find(|p| p.name.eq(name)) {
+            panel.connected = Some(connected);
+            panel.last_success = Some(Utc::now());
+        }
+    }
+}
+
+/// Sends `state` to the systemd notification socket, nothing when not started by systemd.
+fn sd_notify(state: &str) {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let result = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+        Some(name) => send_abstract(&socket, name, state),
+        None => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(err) = result {
+        error!("Failed to notify systemd: {}", err);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> std::io::Result<usize> {
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr)
+}
+
+// abstract socket names exist on Linux only
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, _name: &str, _state: &str) -> std::io::Result<usize> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+async fn handle(req: Request<Body>, health: Health) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/health") => {
+            let report = health.report();
+            let status = match report.healthy {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(&report).unwrap()))
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+impl HealthConfig {
+    /// Probes every panel, serves `/health` and keeps the systemd watchdog fed while healthy.
+    pub async fn serve(
+        &self,
+        panels: Vec<Panel>,
+        mqtt: watch::Receiver<bool>,
+    ) -> Result<(), hyper::Error> {
+        let health = Health {
+            mqtt,
+            panels: Arc::new(Mutex::new(
+                panels
+                    .iter()
+                    .map(|p| PanelHealth {
+                        name: p.name.to_string(),
+                        connected: None,
+                        last_success: None,
+                        healthy: false,
+                    })
+                    .collect(),
+            )),
+            max_age: chrono::Duration::seconds(self.max_age as i64),
+        };
+
+        for panel in panels {
+            let health = health.clone();
+            let interval = Duration::from_secs(self.interval);
+            let context = LogContext::panel(&panel.name);
+            tokio::spawn(context.scope(async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    match panel.backend.status().await {
+                        Ok(status) => health.succeeded(&panel.name, status.connected),
+                        Err(err) => warn!("Health probe failed: {}", err),
+                    }
+                }
+            }));
+        }
+
+        if self.systemd {
+            tokio::spawn(notify_systemd(health.clone()));
+        }
+
+        let make_svc = make_service_fn(move |_conn| {
+            let health = health.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, health.clone()))) }
+        });
+
+        let server = Server::try_bind(&self.bind)?.serve(make_svc);
+        info!("Health endpoint listening on {}", self.bind);
+        server.await
+    }
+
+    /// `healthcheck` subcommand, queries `/health` of the running gateway.
+    pub async fn check(&self) -> Result<HealthReport, reqwest::Error> {
+        let mut addr = self.bind;
+        if addr.ip().is_unspecified() {
+            addr.set_ip([127, 0, 0, 1].into());
+        }
+        reqwest::get(format!("http://{}/health", addr))
+            .await?
+            .json::<HealthReport>()
+            .await
+    }
+}
+
+/// READY once connected to the broker, then WATCHDOG at half the systemd interval while the
+/// broker is connected. Panel health is left to `/health`, a panel or cloud outage should not
+/// get the gateway restarted.
+async fn notify_systemd(health: Health) {
+    let mut mqtt = health.mqtt.clone();
+    while !*mqtt.borrow_and_update() {
+        if mqtt.changed().await.is_err() {
+            return;
+        }
+    }
+    sd_notify("READY=1");
+    info!("Notified systemd READY");
+
+    let usec = match std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|u| u.parse::<u64>().ok())
+    {
+        Some(usec) => usec,
+        None => return,
+    };
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    loop {
+        interval.tick().await;
+        match *mqtt.borrow() {
+            true => sd_notify("WATCHDOG=1"),
+            false => debug!("MQTT disconnected, skipping systemd watchdog notification"),
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::audit::audit_log::AuditLog;
+use crate::command::{
+    bypassable_zones, constant_time_eq, execute, receive_request, CommandOutcome, CommandRequest,
+    Decision, InFlight,
+};
+use crate::logging::logger::LogContext;
+use crate::panel::Panel;
+use visonic::{Device, VisonicErr};
+
+#[derive(Clone, Deserialize)]
+pub struct HttpHandlerConfig {
+    pub bind: SocketAddr,
+    pub token: String,
+}
+
+enum Route {
+    Status,
+    Partitions,
+    Devices,
+    Events,
+    Troubles,
+    Zones,
+    Command(String),
+}
+
+impl Route {
+    fn resolve(method: &Method, path: &str) -> Option<Route> {
+        let path = path.trim_end_matches('/');
+        if let (&Method::POST, Some(rest)) = (method, path.strip_prefix("/zones/")) {
+            let (zone, action) = rest.rsplit_once('/')?;
+            // locations are sent encoded, `/zones/Front%20door/bypass`
+            let zone = percent_decode_str(zone).decode_utf8().ok()?;
+            return match action {
+                "bypass" => Some(Route::Command(format!("BYPASS {}", zone))),
+                "unbypass" => Some(Route::Command(format!("UNBYPASS {}", zone))),
+                _ => None,
+            };
+        }
+
+        match (method, path) {
+            (&Method::GET, "/status") => Some(Route::Status),
+            (&Method::GET, "/partitions") => Some(Route::Partitions),
+            (&Method::GET, "/devices") => Some(Route::Devices),
+            (&Method::GET, "/events") => Some(Route::Events),
+            (&Method::GET, "/troubles") => Some(Route::Troubles),
+            (&Method::GET, "/zones") => Some(Route::Zones),
+            (&Method::POST, "/arm/away") => Some(Route::Command("AWAY".to_string())),
+            (&Method::POST, "/arm/stay") => Some(Route::Command("STAY".to_string())),
+            (&Method::POST, "/arm/night") => Some(Route::Command("NIGHT".to_string())),
+            (&Method::POST, "/arm/away_instant") => {
+                Some(Route::Command("AWAY_INSTANT".to_string()))
+            }
+            (&Method::POST, "/arm/stay_instant") => {
+                Some(Route::Command("STAY_INSTANT".to_string()))
+            }
+            (&Method::POST, "/arm/night_instant") => {
+                Some(Route::Command("NIGHT_INSTANT".to_string()))
+            }
+            (&Method::POST, "/arm/latchkey") => Some(Route::Command("LATCHKEY".to_string())),
+            (&Method::POST, "/disarm") => Some(Route::Command("DISARM".to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Optional body of command requests.
+#[derive(Deserialize, Default)]
+struct CommandBody {
+    code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CommandResult {
+    result: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorResult {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct NotReadyResult {
+    error: String,
+    open_zones: Vec<Device>,
+}
+
+fn respond(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, error: String) -> Response<Body> {
+    let body = serde_json::to_string(&ErrorResult { error }).unwrap();
+    respond(status, body)
+}
+
+fn visonic_error_response(err: VisonicErr) -> Response<Body> {
+    error!("HTTP API request failed: {}", err);
+    error_response(StatusCode::BAD_GATEWAY, err.to_string())
+}
+
+fn json_response<T: Serialize>(r: Result<T, VisonicErr>) -> Response<Body> {
+    match r.map(|v| serde_json::to_string(&v)) {
+        Ok(Ok(body)) => respond(StatusCode::OK, body),
+        Ok(Err(err)) => error_response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        Err(err) => visonic_error_response(err),
+    }
+}
+
+// panel responses not yet modelled are passed through as returned by the cloud
+fn raw_json_response(r: Result<String, VisonicErr>) -> Response<Body> {
+    match r {
+        Ok(body) => respond(StatusCode::OK, body),
+        Err(err) => visonic_error_response(err),
+    }
+}
+
+fn command_response(outcome: CommandOutcome) -> Response<Body> {
+    if let (Some(open_zones), Some(error)) = (outcome.open_zones, outcome.reason.clone()) {
+        let body = serde_json::to_string(&NotReadyResult { error, open_zones }).unwrap();
+        return respond(StatusCode::CONFLICT, body);
+    }
+
+    match (outcome.decision, outcome.error) {
+        (_, Some(error)) => error_response(StatusCode::BAD_GATEWAY, error),
+        (Decision::Accepted | Decision::Skipped, None) => respond(
+            StatusCode::OK,
+            serde_json::to_string(&CommandResult {
+                result: outcome.command,
+                reason: outcome.reason,
+            })
+            .unwrap(),
+        ),
+        (Decision::Rejected, None) => error_response(
+            StatusCode::BAD_REQUEST,
+            outcome.reason.unwrap_or_else(|| "rejected".to_string()),
+        ),
+    }
+}
+
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|bearer| constant_time_eq(bearer, token))
+}
+
+/// `/panels/{name}/...` addresses a panel by name, other paths go to the first panel.
+fn select_panel<'a>(panels: &'a [Panel], path: &'a str) -> Option<(&'a Panel, &'a str)> {
+    match path.strip_prefix("/panels/") {
+        Some(rest) => {
+            let (name, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            panels.iter().find(|p| p.name.eq(name)).map(|p| (p, rest))
+        }
+        None => panels.first().map(|p| (p, path)),
+    }
+}
+
+async fn run_command(
+    panel: &Panel,
+    source: String,
+    command: String,
+    body: Body,
+    audit: Option<&AuditLog>,
+) -> Response<Body> {
+    info!("HTTP API command: {}", command);
+    // drained on shutdown until answered
+    let _in_flight = InFlight::track();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) if body.is_empty() => CommandBody::default(),
+        Ok(body) => match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(err) => return error_response(StatusCode::BAD_REQUEST, err.to_string()),
+        },
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    // the route names the command, the code is taken as sent instead of parsed from a payload
+    let payload = match body.code {
+        Some(_) => json!({"action": command, "code": "***"}),
+        None => json!({ "action": command }),
+    };
+    let request = CommandRequest {
+        command,
+        code: body.code,
+    };
+    let received = receive_request(panel, source, payload.to_string(), request);
+    command_response(execute(panel, received, audit).await)
+}
+
+async fn handle(
+    req: Request<Body>,
+    config: HttpHandlerConfig,
+    panels: Vec<Panel>,
+    audit: Option<AuditLog>,
+) -> Result<Response<Body>, Infallible> {
+    if !authorized(&req, &config.token) {
+        return Ok(error_response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized".to_string(),
+        ));
+    }
+
+    let not_found = || error_response(StatusCode::NOT_FOUND, "not found".to_string());
+    let (parts, body) = req.into_parts();
+
+    let (panel, path) = match select_panel(&panels, parts.uri.path()) {
+        Some(selected) => selected,
+        None => return Ok(not_found()),
+    };
+
+    let route = match Route::resolve(&parts.method, path) {
+        Some(route) => route,
+        None => return Ok(not_found()),
+    };
+
+    let backend = panel.backend.as_ref();
+    let response = match route {
+        Route::Status => json_response(backend.status().await),
+        Route::Partitions => json_response(backend.status().await.map(|s| s.partitions)),
+        Route::Devices => json_response(backend.devices().await),
+        Route::Events => json_response(backend.events().await),
+        Route::Troubles => raw_json_response(backend.troubles().await),
+        Route::Zones => json_response(bypassable_zones(backend).await),
+        Route::Command(command) => {
+            let source = format!("http:{}", parts.uri.path());
+            let context = LogContext::panel(&panel.name);
+            context
+                .scope(run_command(panel, source, command, body, audit.as_ref()))
+                .await
+        }
+    };
+
+    Ok(response)
+}
+
+impl HttpHandlerConfig {
+    pub async fn serve(
+        &self,
+        panels: Vec<Panel>,
+        audit: Option<AuditLog>,
+    ) -> Result<(), hyper::Error> {
+        let config = self.clone();
+        let make_svc = make_service_fn(move |_conn| {
+            let config = config.clone();
+            let panels = panels.clone();
+            let audit = audit.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(req, config.clone(), panels.clone(), audit.clone())
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&self.bind)?.serve(make_svc);
+        info!("HTTP API listening on {}", self.bind);
+        server.await
+    }
+}
@@ -0,0 +1,16 @@
+//! Client for the Visonic PowerManage cloud (tycomonitor) REST API.
+//!
+//! [`Visonic`] holds the panel credentials and rate limits, [`Visonic::login`] returns an
+//! [`AuthedVisonic`] session for status, arming and panel queries. [`Visonic::watch`] streams
+//! typed panel changes. The MQTT / HTTP gateway is
+//! built with the default `gateway` feature, depend on the crate with `default-features = false`
+//! for the client alone.
+
+mod visonic;
+
+pub use crate::visonic::guard::{CircuitState, Limits};
+pub use crate::visonic::visonic::{
+    panel_model, AuthedVisonic, Device, DeviceWarning, Event, Partition, PartitionStatus,
+    ResProcessStatus, ResProcessToken, ResStatus, State, StateChange, Visonic, VisonicErr,
+};
+pub use crate::visonic::watcher::{PanelChange, WatchIntervals};
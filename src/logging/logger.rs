@@ -0,0 +1,381 @@
+//! Log output with task context and redaction of secrets.
+//!
+//! Every record is tagged with the [`LogContext`] of the task logging it and secrets are
+//! replaced by `***`, both values registered with [`redact`] and values following known
+//! secret keys (`password`, `user_code`, `User-Token`, `Session-Token`..).
+
+use std::future::Future;
+use std::io::Write;
+use std::sync::RwLock;
+
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+const REDACTED: &str = "***";
+
+/// Keys whose values never reach the log, matched case insensitive.
+const SECRET_KEYS: [&str; 10] = [
+    "password",
+    "user_password",
+    "user_code",
+    "user-token",
+    "user_token",
+    "session-token",
+    "session_token",
+    "access_token",
+    "authorization",
+    "bearer",
+];
+
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+tokio::task_local! {
+    static CONTEXT: LogContext;
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// `[logging]` section, `RUST_LOG` overrides `level` when set.
+#[derive(Clone, Deserialize)]
+pub struct LoggingConfig {
+    #[serde(default = "default_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: default_level(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+/// Context attached to every record logged within `scope`.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct LogContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub panel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_id: Option<String>,
+}
+
+impl LogContext {
+    /// Context of the running task, empty outside of any scope.
+    pub fn current() -> LogContext {
+        CONTEXT.try_with(|c| c.clone()).unwrap_or_default()
+    }
+
+    pub fn panel(name: &str) -> LogContext {
+        LogContext {
+            panel: Some(name.to_string()),
+            ..LogContext::current()
+        }
+    }
+
+    pub fn partition(self, partition: i16) -> LogContext {
+        LogContext {
+            partition: Some(partition),
+            ..self
+        }
+    }
+
+    pub fn command(self, command_id: &str) -> LogContext {
+        LogContext {
+            command_id: Some(command_id.to_string()),
+            ..self
+        }
+    }
+
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CONTEXT.scope(self, f).await
+    }
+
+    fn is_empty(&self) -> bool {
+        self.panel.is_none() && self.partition.is_none() && self.command_id.is_none()
+    }
+}
+
+impl std::fmt::Display for LogContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut fields: Vec<String> = vec![];
+        if let Some(panel) = &self.panel {
+            fields.push(format!("panel={}", panel));
+        }
+        if let Some(partition) = self.partition {
+            fields.push(format!("partition={}", partition));
+        }
+        if let Some(command_id) = &self.command_id {
+            fields.push(format!("command={}", command_id));
+        }
+        write!(f, "{}", fields.join(" "))
+    }
+}
+
+/// Registers a secret, it is replaced wherever it shows up in a record.
+pub fn redact(secret: &str) {
+    if !secret.is_empty() {
+        SECRETS.write().unwrap().push(secret.to_string());
+    }
+}
+
+/// Drops a secret registered with [`redact`] that is no longer used, such as an expired token.
+pub fn unredact(secret: &str) {
+    let mut secrets = SECRETS.write().unwrap();
+    if let Some(i) = secrets.iter().position(|s| s.eq(secret)) {
+        secrets.remove(i);
+    }
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn is_separator(c: char) -> bool {
+    matches!(c, '"' | '\'' | ':' | '=' | ' ' | '\\')
+}
+
+fn is_value_end(c: char) -> bool {
+    matches!(c, '"' | '\'' | ',' | '}' | ']' | ')' | '&' | '\\') || c.is_whitespace()
+}
+
+/// Replaces values following secret keys, `key: value`, `"key":"value"`, `key=value` and
+/// `Bearer value` alike.
+fn redact_keys(message: &str) -> String {
+    let lower = message.to_ascii_lowercase();
+    let mut out = String::with_capacity(message.len());
+    let mut pos = 0;
+
+    while pos < message.len() {
+        let found = SECRET_KEYS
+            .iter()
+            .filter_map(|key| {
+                let mut from = pos;
+                while let Some(i) = lower[from..].find(key) {
+                    let start = from + i;
+                    let end = start + key.len();
+                    let bounded = !lower[..start].ends_with(is_key_char)
+                        && lower[end..].starts_with(is_separator);
+                    if bounded {
+                        return Some((start, end));
+                    }
+                    from = end;
+                }
+                None
+            })
+            .min();
+
+        let end = match found {
+            Some((_, end)) => end,
+            None => break,
+        };
+        let value_start = message.len() - message[end..].trim_start_matches(is_separator).len();
+        let value_end = message[value_start..]
+            .find(is_value_end)
+            .map(|i| value_start + i)
+            .unwrap_or(message.len());
+
+        out.push_str(&message[pos..value_start]);
+        pos = value_start;
+        // `Authorization: Bearer <token>`, the scheme is a key itself
+        if SECRET_KEYS.contains(&&lower[value_start..value_end]) {
+            continue;
+        }
+        if value_end > value_start {
+            out.push_str(REDACTED);
+        }
+        pos = value_end;
+    }
+
+    out.push_str(&message[pos..]);
+    out
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+/// Replaces `secret` where it is not part of a longer word or number, so a short user code
+/// does not hide unrelated digits.
+fn replace_bounded(message: &str, secret: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut pos = 0;
+
+    while let Some(i) = message[pos..].find(secret) {
+        let (start, end) = (pos + i, pos + i + secret.len());
+        let joined = (secret.starts_with(is_word_char) && message[..start].ends_with(is_word_char))
+            || (secret.ends_with(is_word_char) && message[end..].starts_with(is_word_char));
+        out.push_str(&message[pos..start]);
+        out.push_str(if joined { secret } else { REDACTED });
+        pos = end;
+    }
+
+    out.push_str(&message[pos..]);
+    out
+}
+
+pub fn redact_message(message: &str) -> String {
+    let mut message = redact_keys(message);
+    for secret in SECRETS.read().unwrap().iter() {
+        message = replace_bounded(&message, secret);
+    }
+    message
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(flatten)]
+    context: LogContext,
+}
+
+struct Logger {
+    // text output and level filter
+    inner: Box<dyn Log>,
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = redact_message(&record.args().to_string());
+        let context = LogContext::current();
+
+        match self.format {
+            LogFormat::Json => {
+                let line = JsonRecord {
+                    timestamp: Utc::now().to_rfc3339(),
+                    level: record.level().as_str(),
+                    target: record.target(),
+                    message,
+                    context,
+                };
+                if let Ok(line) = serde_json::to_string(&line) {
+                    let _ = writeln!(std::io::stderr(), "{}", line);
+                }
+            }
+            LogFormat::Text => {
+                let prefix = match context.is_empty() {
+                    true => "".to_string(),
+                    false => format!("[{}] ", context),
+                };
+                self.inner.log(
+                    &Record::builder()
+                        .metadata(record.metadata().clone())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .args(format_args!("{}{}", prefix, message))
+                        .build(),
+                )
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+impl LoggingConfig {
+    pub fn init(&self) {
+        let level = self.level.parse().unwrap_or(LevelFilter::Info);
+        let mut builder = pretty_env_logger::formatted_builder();
+        builder.filter_level(level);
+        if let Ok(filters) = std::env::var("RUST_LOG") {
+            builder.parse_filters(&filters);
+        }
+        let inner = builder.build();
+        let max_level = inner.filter();
+
+        let logger = Logger {
+            inner: Box::new(inner),
+            format: self.format,
+        };
+        log::set_boxed_logger(Box::new(logger)).expect("logger is already set");
+        log::set_max_level(max_level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_values_of_secret_keys() {
+        assert_eq!(redact_keys("password=hunter2 next"), "password=*** next");
+        assert_eq!(
+            redact_keys(r#"{"email":"me","user_code":"1234","app_id":"x"}"#),
+            r#"{"email":"me","user_code":"***","app_id":"x"}"#
+        );
+        assert_eq!(
+            redact_keys("Authorization: Bearer abc.def-1"),
+            "Authorization: Bearer ***"
+        );
+        assert_eq!(
+            redact_keys("\"User-Token\": \"5f3a\", \"Session-Token\": \"9b1c\""),
+            "\"User-Token\": \"***\", \"Session-Token\": \"***\""
+        );
+        assert_eq!(
+            redact_keys("?access_token=abc&x=1"),
+            "?access_token=***&x=1"
+        );
+    }
+
+    #[test]
+    fn keeps_keys_that_only_contain_a_secret_key() {
+        assert_eq!(redact_keys("passwords_checked=3"), "passwords_checked=3");
+        assert_eq!(redact_keys("old_password_hint"), "old_password_hint");
+        assert_eq!(redact_keys("no secrets here"), "no secrets here");
+    }
+
+    #[test]
+    fn redacts_registered_secrets_on_word_boundaries() {
+        redact("4821");
+        assert_eq!(
+            redact_message("code 4821, zone 14821 event 48210 (4821)"),
+            "code ***, zone 14821 event 48210 (***)"
+        );
+        redact("s3cr3t/path?k=v");
+        assert_eq!(
+            redact_message("POST https://hooks.example.com/s3cr3t/path?k=v failed"),
+            "POST https://hooks.example.com/*** failed"
+        );
+    }
+
+    #[test]
+    fn stops_redacting_dropped_secrets() {
+        redact("expired-token-77");
+        assert_eq!(redact_message("token expired-token-77"), "token ***");
+        unredact("expired-token-77");
+        assert_eq!(
+            redact_message("token expired-token-77"),
+            "token expired-token-77"
+        );
+    }
+}
@@ -1,26 +1,235 @@
 use std::error::Error;
+use std::time::Duration;
 
-use clap::Parser;
-use log::{error, info, LevelFilter};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use log::{error, info};
 use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
 
-use crate::mqtt::mqtt_handler::MqttHandlerConfig;
-use crate::visonic::visonic::{AuthedVisonic, Visonic, VisonicErr};
+use crate::audit::audit_log::{AuditConfig, AuditFilter, AuditLog};
+use crate::command::{
+    drained, execute, receive, shut_down, ArmingConfig, CodeAttempts, CommandsConfig,
+};
+use crate::connectivity::connectivity_watchdog::ConnectivityConfig;
+use crate::events::event_poller::EventsConfig;
+use crate::health::health_check::HealthConfig;
+use crate::http::http_handler::HttpHandlerConfig;
+use crate::logging::logger::{redact, LogContext, LoggingConfig};
+use crate::mqtt::mqtt_handler::{Message, MqttHandlerConfig, MqttPublisher};
+use crate::panel::{BackendConfig, Panel, PanelConfig, STATE_COMMANDS};
+use crate::queue::command_queue::CommandQueue;
+use crate::schedule::scheduler::ScheduleConfig;
+use crate::state::state_poller::StateConfig;
+use crate::webhooks::webhook_watcher::WebhooksConfig;
+use visonic::CircuitState;
 
+mod audit;
+mod backend;
+mod command;
+mod connectivity;
+mod events;
+mod health;
+mod http;
+mod logging;
 mod mqtt;
-mod visonic;
+mod panel;
+mod queue;
+mod schedule;
+mod state;
+mod webhooks;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct CliArgs {
     #[clap(short, long)]
     config: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print received commands from the audit log as JSON lines
+    Audit {
+        /// Only commands of this panel
+        #[clap(long)]
+        panel: Option<String>,
+        /// Only commands received since, RFC 3339 timestamp
+        #[clap(long)]
+        since: Option<DateTime<Utc>>,
+        /// Print at most this many most recent commands
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// Query the health endpoint of the running gateway, exits non zero when unhealthy
+    Healthcheck,
 }
 
 #[derive(Deserialize)]
 struct Configuration {
     mqtt: MqttHandlerConfig,
-    visonic: Visonic,
+    #[serde(flatten)]
+    backend: BackendConfig,
+    #[serde(default)]
+    panels: Vec<PanelConfig>,
+    http: Option<HttpHandlerConfig>,
+    events: Option<EventsConfig>,
+    webhooks: Option<WebhooksConfig>,
+    audit: Option<AuditConfig>,
+    arming: Option<ArmingConfig>,
+    connectivity: Option<ConnectivityConfig>,
+    health: Option<HealthConfig>,
+    logging: Option<LoggingConfig>,
+    state: Option<StateConfig>,
+    commands: Option<CommandsConfig>,
+    schedule: Option<ScheduleConfig>,
+}
+
+impl Configuration {
+    /// Top level `[visonic]`, `[simulator]` or `[powerlink]` panel with `[mqtt]` topics followed by every
+    /// `[[panels]]` entry.
+    fn panels(&self) -> Vec<Panel> {
+        let mut panels: Vec<Panel> = vec![];
+
+        if self.backend.is_configured() {
+            // required topics are checked by `validate`
+            let topic = |t: &Option<String>| t.clone().unwrap_or_default();
+            let backend = self.backend.start("default");
+            panels.push(Panel {
+                name: "default".to_string(),
+                command_topic: topic(&self.mqtt.command_topic),
+                status_topic: topic(&self.mqtt.status_topic),
+                info_topic: topic(&self.mqtt.info_topic),
+                cloud_topic: self.mqtt.cloud_topic.clone(),
+                zones_topic: self.mqtt.zones_topic.clone(),
+                availability_topic: self.mqtt.availability_topic.clone(),
+                state_topic: self.mqtt.state_topic.clone(),
+                state_commands: broadcast::channel(STATE_COMMANDS).0,
+                queue: CommandQueue::shared(&backend.panel_id(), backend.partition()),
+                code_attempts: CodeAttempts::default(),
+                backend,
+                events: self.events.clone(),
+                webhooks: self.webhooks.clone(),
+                arming: self.arming.clone(),
+                connectivity: self.connectivity.clone(),
+                state: self.state.clone(),
+                commands: self.commands.clone(),
+                schedule: self.schedule.clone(),
+            });
+        }
+
+        panels.extend(self.panels.iter().cloned().map(Panel::from));
+        panels
+    }
+}
+
+impl Configuration {
+    /// Checks what serde can not: topics of the top level panel, one backend per panel, unique
+    /// panel names and command topics and the schedule rules.
+    fn validate(&self) -> Result<(), String> {
+        let mut names = vec![];
+        let mut topics = vec![];
+
+        if self.backend.is_configured() {
+            let required = [
+                ("command_topic", &self.mqtt.command_topic),
+                ("status_topic", &self.mqtt.status_topic),
+                ("info_topic", &self.mqtt.info_topic),
+            ];
+            for (name, topic) in required {
+                if topic.is_none() {
+                    return Err(format!("mqtt.{} is required with a top level panel", name));
+                }
+            }
+            if self.backend.configured() > 1 {
+                return Err(
+                    "only one of [visonic], [simulator] or [powerlink] sections is allowed"
+                        .to_string(),
+                );
+            }
+            names.push("default".to_string());
+            topics.extend(self.mqtt.command_topic.clone());
+        }
+        if let Some(schedule) = &self.schedule {
+            schedule.validate(self.commands.as_ref(), self.backend.simulator.is_some())?;
+        }
+
+        for panel in &self.panels {
+            if panel.backend.configured() != 1 {
+                return Err(format!(
+                    "panel {} needs exactly one of [visonic], [simulator] or [powerlink] sections",
+                    panel.name
+                ));
+            }
+            if let Some(schedule) = &panel.schedule {
+                schedule
+                    .validate(panel.commands.as_ref(), panel.backend.simulator.is_some())
+                    .map_err(|err| format!("panel {}: {}", panel.name, err))?;
+            }
+            names.push(panel.name.to_string());
+            topics.push(panel.command_topic());
+        }
+
+        if names.is_empty() {
+            return Err(
+                "No panels configured, add [visonic], [simulator], [powerlink] or [[panels]] section"
+                    .to_string(),
+            );
+        }
+        if let Some(name) = duplicate(&names) {
+            return Err(format!("panel name {} is used more than once", name));
+        }
+        if let Some(topic) = duplicate(&topics) {
+            return Err(format!(
+                "command topic {} is used by more than one panel",
+                topic
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn duplicate(values: &[String]) -> Option<&String> {
+    values
+        .iter()
+        .enumerate()
+        .find(|(i, v)| values[..*i].contains(v))
+        .map(|(_, v)| v)
+}
+
+impl Configuration {
+    /// Configured passwords, codes and tokens, kept out of the log.
+    fn secrets(&self) -> Vec<String> {
+        let mut secrets = vec![self.mqtt.password.to_string()];
+        secrets.extend(self.http.iter().map(|h| h.token.to_string()));
+
+        let commands =
+            std::iter::once(&self.commands).chain(self.panels.iter().map(|p| &p.commands));
+        secrets.extend(commands.flatten().filter_map(|c| c.code.clone()));
+
+        let webhooks =
+            std::iter::once(&self.webhooks).chain(self.panels.iter().map(|p| &p.webhooks));
+        secrets.extend(
+            webhooks
+                .flatten()
+                .flat_map(|w| &w.targets)
+                .flat_map(|t| t.secrets()),
+        );
+
+        let backends = std::iter::once(&self.backend).chain(self.panels.iter().map(|p| &p.backend));
+        for backend in backends {
+            if let Some(visonic) = &backend.visonic {
+                secrets.push(visonic.user_password.to_string());
+                secrets.push(visonic.user_code.to_string());
+            }
+            if let Some(powerlink) = &backend.powerlink {
+                secrets.push(powerlink.user_code.to_string());
+            }
+        }
+        secrets
+    }
 }
 
 fn read_config(config_path: &String) -> std::io::Result<Configuration> {
@@ -31,108 +240,285 @@ fn read_config(config_path: &String) -> std::io::Result<Configuration> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::formatted_builder()
-        .filter_level(LevelFilter::Debug)
-        .init();
-
     let args = CliArgs::parse();
     let config = read_config(&args.config).unwrap();
 
-    info!("Loading config from");
+    config.logging.clone().unwrap_or_default().init();
+    config.secrets().iter().for_each(|s| redact(s));
+    info!("Loaded config from {}", args.config);
 
-    let visonic = config.visonic.login().await.unwrap();
-
-    let s = visonic.status_txt().await.unwrap();
-    info!("STATUS: {}", s);
-
-    let s = visonic.status().await.unwrap();
-    info!("STATUS: {}", s.connected);
-
-    let s = visonic.alarms().await.unwrap();
-    info!("alarms: {:?}", s);
-
-    let s = visonic.alerts().await.unwrap();
-    info!("alerts: {:?}", s);
-
-    let s = visonic.troubles().await.unwrap();
-    info!("troubles: {:?}", s);
-
-    let panel_info = visonic.panel_info().await.unwrap();
-    info!("panel_info: {}", panel_info);
+    if let Some(Command::Healthcheck) = args.command {
+        let health = config.health.expect("[health] section is not configured");
+        match health.check().await {
+            Ok(report) => {
+                println!("{}", serde_json::to_string(&report)?);
+                if !report.healthy {
+                    std::process::exit(1);
+                }
+            }
+            Err(err) => {
+                eprintln!("Health check failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
 
-    let s = visonic.wakeup_sms().await.unwrap();
-    info!("wakeup_sms: {:?}", s);
+    if let Some(Command::Audit {
+        panel,
+        since,
+        limit,
+    }) = args.command
+    {
+        let audit = config.audit.expect("[audit] section is not configured");
+        let filter = AuditFilter {
+            panel,
+            since,
+            limit,
+        };
+        for record in audit.open().query(&filter)? {
+            println!("{}", serde_json::to_string(&record)?);
+        }
+        return Ok(());
+    }
 
-    let s = visonic.devices().await.unwrap();
-    info!("devices: {:?}", s);
+    if let Err(err) = config.validate() {
+        error!("Invalid config {}: {}", args.config, err);
+        std::process::exit(1);
+    }
 
-    let s = visonic.locations().await.unwrap();
-    info!("locations: {:?}", s);
+    let audit = config.audit.as_ref().map(|a| a.open());
 
-    let s = visonic.events().await.unwrap();
-    info!("events: {:?}", s);
+    let panels = config.panels();
 
     let mut connection = config
         .mqtt
-        .connect()
+        .connect(panels.iter().map(|p| p.command_topic.to_string()).collect())
         .await
         .expect("Could not connect to MQTT");
     info!("Connected to MQTT broker");
 
+    if let Some(http) = config.http.clone() {
+        let panels = panels.clone();
+        let audit = audit.clone();
+        tokio::spawn(async move {
+            if let Err(err) = http.serve(panels, audit).await {
+                error!("HTTP API failed: {}", err);
+            }
+        });
+    }
+
+    if let Some(health) = config.health.clone() {
+        let panels = panels.clone();
+        let mqtt = connection.connected();
+        tokio::spawn(async move {
+            if let Err(err) = health.serve(panels, mqtt).await {
+                error!("Health endpoint failed: {}", err);
+            }
+        });
+    }
+
+    for panel in panels.iter().cloned() {
+        let publisher = connection.publisher();
+        let audit = audit.clone();
+        let context = LogContext::panel(&panel.name);
+        tokio::spawn(context.scope(async move { start_panel(panel, publisher, audit).await }));
+    }
+
     connection
-        .publish(config.mqtt.info_topic, panel_info)
-        .await
-        .unwrap();
-    connection
-        .on_message(|msg| {
-            let visonic = config.visonic.clone();
-            let command = msg.payload.to_string();
-            async move {
-                match visonic.login().await {
-                    Ok(visonic) => dispatch_command(command, visonic.clone()).await,
-                    Err(err) => {
-                        error!("Failed to login to visonic {}", err);
-                        None
+        .on_message(
+            |msg| {
+                let panel = panels
+                    .iter()
+                    .find(|p| p.command_topic.eq(&msg.topic))
+                    .cloned();
+                // queued before spawning, commands of a panel keep their order
+                let received = panel
+                    .as_ref()
+                    .map(|panel| receive(panel, msg.topic, msg.payload));
+                let audit = audit.clone();
+                async move {
+                    let panel = panel?;
+                    let outcome = execute(&panel, received?, audit.as_ref()).await;
+                    match (outcome.reply(), &outcome.zones, &panel.zones_topic) {
+                        (Some(payload), _, _) => Some(Message {
+                            topic: panel.status_topic.to_string(),
+                            payload,
+                        }),
+                        (None, Some(zones), Some(topic)) => Some(Message {
+                            topic: topic.to_string(),
+                            payload: serde_json::to_string(zones).unwrap(),
+                        }),
+                        _ => None,
                     }
                 }
-            }
-        })
+            },
+            shutdown_signal(),
+        )
         .await;
 
+    info!("Shutting down");
+    shut_down();
+    let drain_timeout = Duration::from_secs(config.mqtt.drain_timeout);
+    if let Err(err) = connection.close(drain_timeout, drained()).await {
+        error!("Failed to disconnect from MQTT: {}", err);
+    }
+
     Ok(())
 }
 
-fn log_unwrap(cmd: String, r: Result<(), VisonicErr>) -> Option<String> {
-    return match r {
-        Ok(_) => Some(cmd),
-        Err(err) => {
-            error!("Failure {}: {}", cmd, err);
-            Some("ERROR".to_string())
-        }
-    };
+/// Completes on SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+    }
 }
 
-async fn dispatch_command(command: String, visonic: AuthedVisonic) -> Option<String> {
-    match command {
-        s if s.eq("AWAY") => {
-            let arm = visonic.arm().await;
-            return log_unwrap("AWAY".to_string(), arm);
-        }
-        s if s.eq("DISARM") => {
-            let arm = visonic.disarm().await;
-            return log_unwrap("DISARM".to_string(), arm);
-        }
-        s if s.eq("NIGHT") => {
-            let arm = visonic.arm_night().await;
-            return log_unwrap("NIGHT".to_string(), arm);
-        }
-        s if s.eq("STAY") => {
-            let arm = visonic.arm_stay().await;
-            return log_unwrap("STAY".to_string(), arm);
+/// Publishes panel info and starts pollers of a single panel, failures stay within the panel.
+async fn start_panel(panel: Panel, publisher: MqttPublisher, audit: Option<AuditLog>) {
+    if let (Some(topic), Some(mut circuit)) = (panel.cloud_topic.clone(), panel.backend.circuit()) {
+        let publisher = publisher.clone();
+        tokio::spawn(LogContext::current().scope(async move {
+            let mut published = "";
+            loop {
+                let health = match *circuit.borrow() {
+                    CircuitState::Closed => "OK",
+                    CircuitState::Open | CircuitState::HalfOpen => "DEGRADED",
+                };
+                if health.ne(published) {
+                    match publisher
+                        .publish(topic.to_string(), health.to_string())
+                        .await
+                    {
+                        Ok(_) => published = health,
+                        Err(err) => error!("Error publishing cloud health: {}", err),
+                    }
+                }
+                if circuit.changed().await.is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    match panel.describe().await {
+        Ok(panel_info) => {
+            if let Err(err) = publisher
+                .publish(panel.info_topic.to_string(), panel_info)
+                .await
+            {
+                error!("Error publishing panel info: {}", err);
+            }
         }
-        s => {
-            info!("unknown mqtt command: {}", s.to_string());
-            None
+        Err(err) => error!("Failed to describe panel: {}", err),
+    }
+
+    if let Some(events) = panel.events.clone() {
+        let backend = panel.backend.clone();
+        let publisher = publisher.clone();
+        tokio::spawn(LogContext::current().scope(async move {
+            if let Err(err) = events.poll(backend, publisher).await {
+                error!("Event polling failed: {}", err);
+            }
+        }));
+    }
+
+    if let Some(webhooks) = panel.webhooks.clone() {
+        let backend = panel.backend.clone();
+        tokio::spawn(LogContext::current().scope(async move { webhooks.watch(backend).await }));
+    }
+
+    if let (Some(state), Some(topic)) = (panel.state.clone(), panel.state_topic.clone()) {
+        let backend = panel.backend.clone();
+        let publisher = publisher.clone();
+        let commands = panel.state_commands.subscribe();
+        tokio::spawn(
+            LogContext::current()
+                .scope(async move { state.poll(backend, publisher, topic, commands).await }),
+        );
+    }
+
+    if let Some(schedule) = panel.schedule.clone() {
+        let panel = panel.clone();
+        let publisher = publisher.clone();
+        tokio::spawn(
+            LogContext::current().scope(async move { schedule.run(panel, publisher, audit).await }),
+        );
+    }
+
+    if let Some(connectivity) = panel.connectivity.clone() {
+        let backend = panel.backend.clone();
+        let topic = panel.availability_topic.clone();
+        tokio::spawn(
+            LogContext::current()
+                .scope(async move { connectivity.watch(backend, publisher, topic).await }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_every_secret_of_the_config() {
+        let config: Configuration = toml::from_str(
+            r#"
+            [mqtt]
+            id = "gateway"
+            host = "127.0.0.1"
+            port = 1883
+            username = "user"
+            password = "mqtt-pass"
+            command_topic = "/alarm/cmd"
+            status_topic = "/alarm/status"
+            info_topic = "/alarm/info"
+            lwt_topic = "/alarm/lwt"
+
+            [simulator]
+            panel_id = "sim"
+            partitions = [1]
+
+            [http]
+            bind = "127.0.0.1:8080"
+            token = "http-token"
+
+            [webhooks]
+            interval = 30
+            [[webhooks.targets]]
+            url = "https://example.com/hook?token=top-token"
+            headers = { Authorization = "Bearer top-header" }
+
+            [[panels]]
+            name = "cottage"
+            topic_prefix = "/cottage"
+            [panels.simulator]
+            panel_id = "cottage"
+            partitions = [1]
+            [panels.webhooks]
+            interval = 30
+            [[panels.webhooks.targets]]
+            url = "https://example.com/panel"
+            headers = { X-Api-Key = "panel-key" }
+            "#,
+        )
+        .unwrap();
+
+        let secrets = config.secrets();
+        for secret in [
+            "mqtt-pass",
+            "http-token",
+            "token=top-token",
+            "Bearer top-header",
+            "panel-key",
+        ] {
+            assert!(
+                secrets.iter().any(|s| s == secret),
+                "{} is not registered",
+                secret
+            );
         }
     }
 }
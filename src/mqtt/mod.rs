@@ -1 +1 @@
-pub mod mqtt_handler;
\ No newline at end of file
+pub mod mqtt_handler;
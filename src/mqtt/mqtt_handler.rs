@@ -1,13 +1,23 @@
-use log::error;
-use rumqttc::{AsyncClient, ClientError, Event, EventLoop, Incoming, LastWill, MqttOptions, QoS};
+use log::{error, warn};
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, Incoming, LastWill, MqttOptions, Outgoing, QoS,
+};
 use serde::Deserialize;
 use std::fmt::{Display, Formatter};
 use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
 use tokio::task;
 use tokio::task::{JoinError, JoinHandle};
 
 const LWT_OFFLINE: &str = "OFFLINE";
 const LWT_ONLINE: &str = "ONLINE";
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+fn default_drain_timeout() -> u64 {
+    30
+}
 
 #[derive(Clone, Deserialize)]
 pub struct MqttHandlerConfig {
@@ -16,32 +26,107 @@ pub struct MqttHandlerConfig {
     pub port: u16,
     pub username: String,
     pub password: String,
-    pub command_topic: String,
-    pub status_topic: String,
-    pub info_topic: String,
+    pub command_topic: Option<String>,
+    pub status_topic: Option<String>,
+    pub info_topic: Option<String>,
+    pub cloud_topic: Option<String>,
+    pub zones_topic: Option<String>,
+    pub availability_topic: Option<String>,
+    pub state_topic: Option<String>,
     pub lwt_topic: String,
+    /// Seconds to wait for in-flight commands on shutdown.
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
 }
 
 pub struct MqttAsyncConnection {
-    config: MqttHandlerConfig,
     client: AsyncClient,
     connection: EventLoop,
+    lwt_topic: String,
+    command_topics: Vec<String>,
+    // every running command holds a sender, `drained` completes once all of them are done
+    in_flight: mpsc::Sender<()>,
+    drained: mpsc::Receiver<()>,
+    connected: watch::Sender<bool>,
 }
 
-impl MqttAsyncConnection {
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: AsyncClient,
+}
+
+impl MqttPublisher {
     pub async fn publish(&self, topic: String, payload: String) -> Result<(), ClientError> {
         self.client
             .publish(topic, QoS::AtLeastOnce, true, payload)
             .await
     }
 
-    pub async fn on_message<F, Fut>(&mut self, handler: F)
+    pub async fn publish_transient(
+        &self,
+        topic: String,
+        payload: String,
+    ) -> Result<(), ClientError> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+    }
+}
+
+#[cfg(test)]
+impl MqttPublisher {
+    /// Publisher without broker, its messages are read from `requests_rx` of the event loop.
+    pub fn detached() -> (MqttPublisher, EventLoop) {
+        let (client, connection) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 64);
+        (MqttPublisher { client }, connection)
+    }
+}
+
+impl MqttAsyncConnection {
+    /// Broker connection state, updated while messages are handled.
+    pub fn connected(&self) -> watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    pub fn publisher(&self) -> MqttPublisher {
+        MqttPublisher {
+            client: self.client.clone(),
+        }
+    }
+
+    /// Runs `handler` for every incoming message on its own task, so a slow panel does not
+    /// hold up the others, and publishes the returned reply. A lost connection is reported as
+    /// not connected and retried, the command topics are subscribed again once reconnected.
+    /// Stops taking messages once `shutdown` completes, commands still running are left to
+    /// `close`.
+    pub async fn on_message<F, Fut, S>(&mut self, handler: F, shutdown: S)
     where
         F: Fn(Message) -> Fut,
-        Fut: Future<Output = Option<String>>,
+        Fut: Future<Output = Option<Message>> + Send + 'static,
+        S: Future<Output = ()>,
     {
+        tokio::pin!(shutdown);
+        let mut reconnecting = false;
+
         loop {
-            let event = &self.connection.poll().await.unwrap(); //its ok to fail here
+            let event = tokio::select! {
+                event = self.connection.poll() => event,
+                _ = &mut shutdown => break,
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    error!("MQTT connection failed, reconnecting: {}", err);
+                    self.connected.send_replace(false);
+                    reconnecting = true;
+                    // the next poll reconnects
+                    tokio::select! {
+                        _ = tokio::time::sleep(RECONNECT_DELAY) => continue,
+                        _ = &mut shutdown => break,
+                    }
+                }
+            };
             match event {
                 Event::Incoming(Incoming::Publish(p)) => {
                     let r = std::str::from_utf8(&p.payload).map(|s| Message {
@@ -50,26 +135,113 @@ impl MqttAsyncConnection {
                     });
 
                     match r {
-                        Ok(msg) => match handler(msg).await {
-                            Some(msg) => {
-                                let pub_result = &self
-                                    .client
-                                    .publish(&self.config.status_topic, QoS::AtLeastOnce, true, msg)
-                                    .await;
-
-                                match pub_result {
-                                    Ok(_) => (),
-                                    Err(err) => error!("Error publishing to mqtt: {}", err),
+                        Ok(msg) => {
+                            let reply = handler(msg);
+                            let client = self.client.clone();
+                            // held until the reply is published, see `close`
+                            let in_flight = self.in_flight.clone();
+                            task::spawn(async move {
+                                if let Some(msg) = reply.await {
+                                    let pub_result = &client
+                                        .publish(msg.topic, QoS::AtLeastOnce, true, msg.payload)
+                                        .await;
+
+                                    match pub_result {
+                                        Ok(_) => (),
+                                        Err(err) => error!("Error publishing to mqtt: {}", err),
+                                    }
                                 }
-                            }
-                            None => (),
-                        },
+                                drop(in_flight);
+                            });
+                        }
                         Err(err) => error!("Failed to decode MQTT message: {}", err),
                     }
                 }
-                _ => async { () }.await,
+                Event::Incoming(Incoming::ConnAck(ack)) => {
+                    // a clean session lost the subscriptions
+                    if reconnecting && !ack.session_present {
+                        for topic in &self.command_topics {
+                            if let Err(err) = self.client.try_subscribe(topic, QoS::ExactlyOnce) {
+                                error!("Failed to subscribe {}: {}", topic, err);
+                            }
+                        }
+                    }
+                    reconnecting = false;
+                    self.connected.send_replace(true);
+                }
+                Event::Incoming(Incoming::Disconnect) => {
+                    self.connected.send_replace(false);
+                    reconnecting = true;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Unsubscribes the command topics, waits up to `drain_timeout` for the replies of MQTT
+    /// commands and for `commands` received otherwise, then publishes `OFFLINE` to the lwt
+    /// topic and disconnects from the broker.
+    pub async fn close<C>(
+        mut self,
+        drain_timeout: Duration,
+        commands: C,
+    ) -> Result<(), HandlerError>
+    where
+        C: Future<Output = ()>,
+    {
+        // sent by the event loop polled below, the broker stops delivering new commands
+        for topic in &self.command_topics {
+            if let Err(err) = self.client.try_unsubscribe(topic) {
+                error!("Failed to unsubscribe {}: {}", topic, err);
+            }
+        }
+
+        let (in_flight, mut drained) = (self.in_flight, self.drained);
+        drop(in_flight);
+        let drained = async {
+            drained.recv().await;
+            commands.await
+        };
+        tokio::pin!(drained);
+
+        let deadline = tokio::time::sleep(drain_timeout);
+        tokio::pin!(deadline);
+
+        // the event loop keeps running so replies get delivered, new commands are ignored
+        loop {
+            tokio::select! {
+                _ = &mut drained => break,
+                event = self.connection.poll() => {
+                    if let Err(err) = event {
+                        error!("MQTT connection failed while draining commands: {}", err);
+                        break;
+                    }
+                }
+                _ = &mut deadline => {
+                    warn!("In-flight commands did not finish within {:?}", drain_timeout);
+                    break;
+                }
             }
         }
+
+        self.client
+            .publish(&self.lwt_topic, QoS::AtLeastOnce, true, LWT_OFFLINE)
+            .await?;
+        self.client.disconnect().await?;
+
+        let flush = async {
+            loop {
+                match self.connection.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                    Ok(_) => (),
+                }
+            }
+        };
+        if tokio::time::timeout(FLUSH_TIMEOUT, flush).await.is_err() {
+            warn!("Timed out disconnecting from MQTT broker");
+        }
+        self.connected.send_replace(false);
+        Ok(())
     }
 }
 
@@ -94,19 +266,19 @@ impl From<ClientError> for HandlerError {
 }
 
 impl MqttHandlerConfig {
-    pub async fn connect(&self) -> Result<MqttAsyncConnection, HandlerError> {
+    pub async fn connect(
+        &self,
+        command_topics: Vec<String>,
+    ) -> Result<MqttAsyncConnection, HandlerError> {
         async fn do_subscribe(
             client: AsyncClient,
             eventloop: EventLoop,
-            config: MqttHandlerConfig,
+            command_topics: Vec<String>,
         ) -> Result<(AsyncClient, EventLoop), ClientError> {
-            match client
-                .subscribe(config.command_topic, QoS::ExactlyOnce)
-                .await
-            {
-                Ok(_) => Ok((client, eventloop)),
-                Err(e) => Err(e),
+            for topic in command_topics {
+                client.subscribe(topic, QoS::ExactlyOnce).await?;
             }
+            Ok((client, eventloop))
         }
 
         let mut mqttoptions = MqttOptions::new(&self.id, &self.host, self.port);
@@ -118,23 +290,31 @@ impl MqttHandlerConfig {
             true,
         ));
 
-        let (client, connection) = AsyncClient::new(mqttoptions, 10);
+        // requests are queued until the event loop is polled, leave room for every subscription
+        let (client, connection) = AsyncClient::new(mqttoptions, 10 + command_topics.len());
 
         client
             .publish(&self.lwt_topic, QoS::AtLeastOnce, true, LWT_ONLINE)
             .await?;
 
-        let conf = self.clone();
+        let topics = command_topics.clone();
         let x: JoinHandle<Result<(AsyncClient, EventLoop), ClientError>> =
-            task::spawn(async move { do_subscribe(client, connection, conf.clone()).await });
+            task::spawn(async move { do_subscribe(client, connection, topics).await });
 
         match x.await {
             Ok(join) => match join {
-                Ok(r) => Ok(MqttAsyncConnection {
-                    connection: r.1,
-                    client: r.0,
-                    config: self.clone(),
-                }),
+                Ok(r) => {
+                    let (in_flight, drained) = mpsc::channel(1);
+                    Ok(MqttAsyncConnection {
+                        connection: r.1,
+                        client: r.0,
+                        lwt_topic: self.lwt_topic.to_string(),
+                        command_topics,
+                        in_flight,
+                        drained,
+                        connected: watch::channel(false).0,
+                    })
+                }
                 Err(e) => Err(HandlerError::Mqtt(e)),
             },
             Err(e) => Err(HandlerError::System(e)),
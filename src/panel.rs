@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use log::info;
+use serde::Deserialize;
+use tokio::sync::broadcast;
+
+use crate::backend::cloud_backend::CloudBackend;
+use crate::backend::panel_backend::PanelBackend;
+use crate::backend::powerlink_backend::PowerLinkConfig;
+use crate::backend::simulator_backend::SimulatorConfig;
+use crate::command::{ArmingConfig, CodeAttempts, CommandsConfig};
+use crate::connectivity::connectivity_watchdog::ConnectivityConfig;
+use crate::events::event_poller::EventsConfig;
+use crate::queue::command_queue::CommandQueue;
+use crate::schedule::scheduler::ScheduleConfig;
+use crate::state::state_poller::{StateCommand, StateConfig};
+use crate::webhooks::webhook_watcher::WebhooksConfig;
+use visonic::{Visonic, VisonicErr};
+
+/// State commands the state poller may lag behind, it polls anyway once it lagged.
+pub const STATE_COMMANDS: usize = 16;
+
+/// One `[[panels]]` entry, topics are derived from `topic_prefix`.
+#[derive(Clone, Deserialize)]
+pub struct PanelConfig {
+    pub name: String,
+    pub topic_prefix: String,
+    #[serde(flatten)]
+    pub backend: BackendConfig,
+    pub events: Option<EventsConfig>,
+    pub webhooks: Option<WebhooksConfig>,
+    pub arming: Option<ArmingConfig>,
+    pub connectivity: Option<ConnectivityConfig>,
+    pub state: Option<StateConfig>,
+    pub commands: Option<CommandsConfig>,
+    pub schedule: Option<ScheduleConfig>,
+}
+
+/// Panel served by the gateway with its resolved topics.
+#[derive(Clone)]
+pub struct Panel {
+    pub name: String,
+    pub command_topic: String,
+    pub status_topic: String,
+    pub info_topic: String,
+    /// Cloud API health, `OK` or `DEGRADED` while the circuit breaker is not closed.
+    pub cloud_topic: Option<String>,
+    /// Bypassable zones, published after `ZONES`, `BYPASS` and `UNBYPASS` commands.
+    pub zones_topic: Option<String>,
+    /// Panel connectivity to the cloud, `ONLINE` or `OFFLINE`.
+    pub availability_topic: Option<String>,
+    /// Partition states, one `<state_topic>/<partition>` topic each.
+    pub state_topic: Option<String>,
+    /// State commands sent to the panel, the state poller refreshes right after them.
+    pub state_commands: broadcast::Sender<StateCommand>,
+    /// Runs commands one at a time, shared by MQTT and HTTP.
+    pub queue: CommandQueue,
+    pub code_attempts: CodeAttempts,
+    pub backend: Arc<dyn PanelBackend>,
+    pub events: Option<EventsConfig>,
+    pub webhooks: Option<WebhooksConfig>,
+    pub arming: Option<ArmingConfig>,
+    pub connectivity: Option<ConnectivityConfig>,
+    pub state: Option<StateConfig>,
+    pub commands: Option<CommandsConfig>,
+    pub schedule: Option<ScheduleConfig>,
+}
+
+/// Backend sections of a panel, exactly one of them has to be configured.
+#[derive(Clone, Deserialize)]
+pub struct BackendConfig {
+    pub visonic: Option<Visonic>,
+    pub simulator: Option<SimulatorConfig>,
+    pub powerlink: Option<PowerLinkConfig>,
+}
+
+impl BackendConfig {
+    pub fn is_configured(&self) -> bool {
+        self.configured() > 0
+    }
+
+    /// Number of configured backend sections.
+    pub fn configured(&self) -> usize {
+        [
+            self.visonic.is_some(),
+            self.simulator.is_some(),
+            self.powerlink.is_some(),
+        ]
+        .iter()
+        .filter(|c| **c)
+        .count()
+    }
+
+    pub fn start(&self, name: &str) -> Arc<dyn PanelBackend> {
+        match (&self.visonic, &self.simulator, &self.powerlink) {
+            (Some(visonic), None, None) => Arc::new(CloudBackend::new(visonic.clone())),
+            (None, Some(simulator), None) => Arc::new(simulator.start()),
+            (None, None, Some(powerlink)) => Arc::new(powerlink.start()),
+            _ => panic!(
+                "panel {} needs exactly one of [visonic], [simulator] or [powerlink] sections",
+                name
+            ),
+        }
+    }
+}
+
+impl PanelConfig {
+    pub fn command_topic(&self) -> String {
+        format!("{}/cmd", self.topic_prefix.trim_end_matches('/'))
+    }
+}
+
+impl From<PanelConfig> for Panel {
+    fn from(config: PanelConfig) -> Self {
+        let prefix = config.topic_prefix.trim_end_matches('/');
+        let backend = config.backend.start(&config.name);
+        Panel {
+            command_topic: config.command_topic(),
+            status_topic: format!("{}/status", prefix),
+            info_topic: format!("{}/info", prefix),
+            cloud_topic: Some(format!("{}/cloud", prefix)),
+            zones_topic: Some(format!("{}/zones", prefix)),
+            availability_topic: Some(format!("{}/availability", prefix)),
+            state_topic: Some(format!("{}/state", prefix)),
+            state_commands: broadcast::channel(STATE_COMMANDS).0,
+            queue: CommandQueue::shared(&backend.panel_id(), backend.partition()),
+            code_attempts: CodeAttempts::default(),
+            name: config.name,
+            backend,
+            events: config.events,
+            webhooks: config.webhooks,
+            arming: config.arming,
+            connectivity: config.connectivity,
+            state: config.state,
+            commands: config.commands,
+            schedule: config.schedule,
+        }
+    }
+}
+
+impl Panel {
+    pub fn state_command(&self, command: StateCommand) {
+        // nobody listens without [state] section
+        let _ = self.state_commands.send(command);
+    }
+
+    /// Dumps what the panel reports, returns `panel_info` for the info topic.
+    pub async fn describe(&self) -> Result<String, VisonicErr> {
+        self.backend.diagnostics().await?;
+
+        let s = self.backend.status().await?;
+        info!("STATUS: {}", s.connected);
+
+        let s = self.backend.alarms().await?;
+        info!("alarms: {:?}", s);
+
+        let s = self.backend.alerts().await?;
+        info!("alerts: {:?}", s);
+
+        let s = self.backend.troubles().await?;
+        info!("troubles: {:?}", s);
+
+        let panel_info = self.backend.panel_info().await?;
+        info!("panel_info: {}", panel_info);
+
+        let s = self.backend.devices().await?;
+        info!("devices: {:?}", s);
+
+        let s = self.backend.events().await?;
+        info!("events: {:?}", s);
+
+        Ok(panel_info)
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// Why a queued command did not run to completion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueErr {
+    /// A newer state command arrived while this one was waiting.
+    Superseded(String),
+    TimedOut(Duration),
+}
+
+impl Display for QueueErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueErr::Superseded(command) => write!(f, "superseded by {}", command),
+            QueueErr::TimedOut(timeout) => write!(f, "timed out after {}s", timeout.as_secs()),
+        }
+    }
+}
+
+/// Queued command, waiting for its turn or running.
+struct Entry {
+    /// Partition the command acts on, every partition when none.
+    partition: Option<u16>,
+    state: bool,
+    running: bool,
+    /// Newer state command that replaced this one while it was waiting.
+    superseded: Option<String>,
+}
+
+impl Entry {
+    /// Whether this command and one acting on `partition` may not run at the same time.
+    fn overlaps(&self, partition: Option<u16>) -> bool {
+        self.partition.is_none() || partition.is_none() || self.partition == partition
+    }
+}
+
+#[derive(Default)]
+struct Lines {
+    next: u64,
+    /// Commands that have not finished or given up yet, by ticket.
+    entries: BTreeMap<u64, Entry>,
+    version: u64,
+}
+
+/// Runs the commands of each partition of a panel one at a time, in the order they arrived,
+/// while commands of other partitions run alongside. A command acting on every partition waits
+/// for the commands of all partitions queued before it and holds up those queued after it. A
+/// state command still waiting for its turn is superseded by any newer state command acting on
+/// its partition, so a `DISARM` replaces a pending `AWAY` while the running command is left to
+/// finish.
+#[derive(Clone)]
+pub struct CommandQueue {
+    lines: Arc<Mutex<Lines>>,
+    // bumped on every change of `lines`
+    changed: Arc<watch::Sender<u64>>,
+    partition: Option<u16>,
+}
+
+impl Default for CommandQueue {
+    fn default() -> Self {
+        CommandQueue {
+            lines: Arc::new(Mutex::new(Lines::default())),
+            changed: Arc::new(watch::channel(0).0),
+            partition: None,
+        }
+    }
+}
+
+/// Place of a command in the queue, given up when dropped.
+pub struct Place {
+    queue: CommandQueue,
+    ticket: u64,
+}
+
+impl CommandQueue {
+    /// Queue of `partition` of panel `panel_id`, shared by every panel entry configured for the
+    /// same panel so commands sent through either of them keep out of each other's way.
+    pub fn shared(panel_id: &str, partition: Option<u16>) -> CommandQueue {
+        static PANELS: OnceLock<Mutex<HashMap<String, CommandQueue>>> = OnceLock::new();
+        let mut panels = PANELS.get_or_init(Default::default).lock().unwrap();
+        let queue = panels.entry(panel_id.to_string()).or_default();
+        queue.for_partition(partition)
+    }
+
+    /// Queue of the same panel for the commands acting on `partition`.
+    pub fn for_partition(&self, partition: Option<u16>) -> CommandQueue {
+        CommandQueue {
+            partition,
+            ..self.clone()
+        }
+    }
+
+    /// Takes the next place in the queue, commands run in the order their places were taken.
+    /// `state` commands supersede the state commands waiting before them on their partition.
+    pub fn enqueue(&self, command: &str, state: bool) -> Place {
+        let mut lines = self.lines.lock().unwrap();
+        let ticket = lines.next;
+        lines.next += 1;
+        if state {
+            let partition = self.partition;
+            lines
+                .entries
+                .values_mut()
+                .filter(|e| e.state && !e.running && e.superseded.is_none())
+                // a command for one partition leaves the others of a panel wide command alone
+                .filter(|e| partition.is_none() || e.partition == partition)
+                .for_each(|e| e.superseded = Some(command.to_string()));
+        }
+        lines.entries.insert(
+            ticket,
+            Entry {
+                partition: self.partition,
+                state,
+                running: false,
+                superseded: None,
+            },
+        );
+        self.notify(&mut lines);
+        Place {
+            queue: self.clone(),
+            ticket,
+        }
+    }
+
+    fn notify(&self, lines: &mut Lines) {
+        lines.version += 1;
+        self.changed.send_replace(lines.version);
+    }
+
+    fn release(&self, ticket: u64) {
+        let mut lines = self.lines.lock().unwrap();
+        lines.entries.remove(&ticket);
+        self.notify(&mut lines);
+    }
+}
+
+impl Place {
+    /// Waits until no command queued before on an overlapping partition is left, unless
+    /// superseded meanwhile.
+    async fn turn(&self) -> Result<(), QueueErr> {
+        let mut changed = self.queue.changed.subscribe();
+        loop {
+            {
+                let mut lines = self.queue.lines.lock().unwrap();
+                let lines = &mut *lines;
+                let entry = &lines.entries[&self.ticket];
+                if let Some(command) = &entry.superseded {
+                    return Err(QueueErr::Superseded(command.to_string()));
+                }
+                let blocked = lines
+                    .entries
+                    .range(..self.ticket)
+                    // superseded commands give up as soon as they see it
+                    .filter(|(_, e)| e.superseded.is_none())
+                    .any(|(_, e)| e.overlaps(entry.partition));
+                if !blocked {
+                    lines.entries.get_mut(&self.ticket).unwrap().running = true;
+                    return Ok(());
+                }
+            }
+            // the queue holds the sender, it is never closed while waiting
+            let _ = changed.changed().await;
+        }
+    }
+
+    /// Waits for the commands queued before on the partition and runs `task`, giving up after
+    /// `timeout` counted from now. A task still running then is no longer waited for, the
+    /// partition is handed to the next command.
+    pub async fn run<Fut: Future>(
+        self,
+        timeout: Duration,
+        task: Fut,
+    ) -> Result<Fut::Output, QueueErr> {
+        let run = async {
+            self.turn().await?;
+            Ok(task.await)
+        };
+        tokio::time::timeout(timeout, run)
+            .await
+            .unwrap_or(Err(QueueErr::TimedOut(timeout)))
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        self.queue.release(self.ticket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Runs `place` until `done` fires, returning when it started through `started`.
+    fn hold(
+        place: Place,
+        started: oneshot::Sender<()>,
+        done: oneshot::Receiver<()>,
+    ) -> tokio::task::JoinHandle<Result<(), QueueErr>> {
+        tokio::spawn(place.run(TIMEOUT, async move {
+            let _ = started.send(());
+            let _ = done.await;
+        }))
+    }
+
+    async fn still_waiting(place: &mut tokio::task::JoinHandle<Result<(), QueueErr>>) -> bool {
+        tokio::time::timeout(Duration::from_secs(1), place)
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn runs_one_command_of_a_partition_at_a_time() {
+        tokio::time::pause();
+        let queue = CommandQueue::default().for_partition(Some(1));
+
+        let (started, running) = oneshot::channel();
+        let (finish, done) = oneshot::channel();
+        let first = hold(queue.enqueue("BYPASS 3", false), started, done);
+        running.await.unwrap();
+
+        let second = queue.enqueue("BYPASS 4", false);
+        let mut waiting = tokio::spawn(second.run(TIMEOUT, async {}));
+        assert!(still_waiting(&mut waiting).await);
+
+        finish.send(()).unwrap();
+        first.await.unwrap().unwrap();
+        waiting.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_commands_of_other_partitions_alongside() {
+        let panel = CommandQueue::default();
+        let (one, two) = (panel.for_partition(Some(1)), panel.for_partition(Some(2)));
+
+        let (started, running) = oneshot::channel();
+        let (finish, done) = oneshot::channel();
+        let first = hold(one.enqueue("AWAY", true), started, done);
+        running.await.unwrap();
+
+        two.enqueue("DISARM", true)
+            .run(TIMEOUT, async {})
+            .await
+            .unwrap();
+        finish.send(()).unwrap();
+        first.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_for_every_partition_with_a_panel_wide_command() {
+        tokio::time::pause();
+        let panel = CommandQueue::default();
+
+        let (started, running) = oneshot::channel();
+        let (finish, done) = oneshot::channel();
+        let first = hold(
+            panel.for_partition(Some(2)).enqueue("AWAY", true),
+            started,
+            done,
+        );
+        running.await.unwrap();
+
+        let all = panel.enqueue("STATUS", false);
+        let mut waiting = tokio::spawn(all.run(TIMEOUT, async {}));
+        assert!(still_waiting(&mut waiting).await);
+
+        finish.send(()).unwrap();
+        first.await.unwrap().unwrap();
+        waiting.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn supersedes_waiting_state_commands_of_the_partition() {
+        let panel = CommandQueue::default();
+        let one = panel.for_partition(Some(1));
+
+        let (started, running) = oneshot::channel();
+        let (finish, done) = oneshot::channel();
+        let first = hold(one.enqueue("STAY", true), started, done);
+        running.await.unwrap();
+
+        let away = one.enqueue("AWAY", true);
+        let other = panel.for_partition(Some(2)).enqueue("AWAY", true);
+        let bypass = one.enqueue("BYPASS 3", false);
+        let disarm = one.enqueue("DISARM", true);
+
+        assert_eq!(
+            away.run(TIMEOUT, async {}).await.unwrap_err(),
+            QueueErr::Superseded("DISARM".to_string())
+        );
+        other.run(TIMEOUT, async {}).await.unwrap();
+
+        // the running command is left to finish
+        finish.send(()).unwrap();
+        first.await.unwrap().unwrap();
+        bypass.run(TIMEOUT, async {}).await.unwrap();
+        disarm.run(TIMEOUT, async {}).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn times_out_waiting_and_running() {
+        tokio::time::pause();
+        let queue = CommandQueue::default();
+
+        let (started, running) = oneshot::channel();
+        let (_finish, done) = oneshot::channel();
+        let first = hold(queue.enqueue("AWAY", true), started, done);
+        running.await.unwrap();
+
+        let timeout = Duration::from_secs(5);
+        let waiting = queue.enqueue("BYPASS 3", false);
+        assert_eq!(
+            waiting.run(timeout, async {}).await.unwrap_err(),
+            QueueErr::TimedOut(timeout)
+        );
+        assert_eq!(
+            first.await.unwrap().unwrap_err(),
+            QueueErr::TimedOut(TIMEOUT)
+        );
+    }
+
+    #[tokio::test]
+    async fn releases_places_given_up() {
+        tokio::time::pause();
+        let queue = CommandQueue::default();
+
+        let first = queue.enqueue("AWAY", true);
+        let second = queue.enqueue("BYPASS 3", false);
+        let mut waiting = tokio::spawn(second.run(TIMEOUT, async {}));
+        assert!(still_waiting(&mut waiting).await);
+
+        // a command refused before its turn drops its place
+        drop(first);
+        waiting.await.unwrap().unwrap();
+
+        // a timed out command hands the partition on
+        let (started, running) = oneshot::channel();
+        let (_finish, done) = oneshot::channel::<()>();
+        let stuck = tokio::spawn(queue.enqueue("AWAY", true).run(
+            Duration::from_secs(5),
+            async move {
+                let _ = started.send(());
+                let _ = done.await;
+            },
+        ));
+        running.await.unwrap();
+        stuck.await.unwrap().unwrap_err();
+        queue
+            .enqueue("DISARM", true)
+            .run(TIMEOUT, async {})
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::audit_log::AuditLog;
+use crate::command::{
+    execute, is_shutting_down, needs_code, receive, resolve, CommandKind, CommandsConfig, Decision,
+    InFlight,
+};
+use crate::logging::logger::LogContext;
+use crate::mqtt::mqtt_handler::MqttPublisher;
+use crate::panel::Panel;
+
+fn default_skip_holidays() -> bool {
+    true
+}
+
+/// Cron field values, `*`, `5`, `1-5`, `*/15`, `5/15`, `0-30/10` and lists of them.
+#[derive(Debug, Clone)]
+struct Field(Vec<bool>);
+
+impl Field {
+    fn parse(field: &str, min: u32, max: u32) -> Result<Field, String> {
+        let mut values = vec![false; max as usize + 1];
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|e| e.to_string())?),
+                None => (part, 1),
+            };
+            let (from, to) = match range.split_once('-') {
+                _ if range == "*" => (min, max),
+                Some((from, to)) => (
+                    from.parse::<u32>().map_err(|e| e.to_string())?,
+                    to.parse::<u32>().map_err(|e| e.to_string())?,
+                ),
+                None => {
+                    let value = range.parse::<u32>().map_err(|e| e.to_string())?;
+                    // `5/10` runs from 5 on
+                    match part.contains('/') {
+                        true => (value, max),
+                        false => (value, value),
+                    }
+                }
+            };
+            if from < min || to > max || from > to || step == 0 {
+                return Err(format!("{} out of range {}-{}", part, min, max));
+            }
+            (from..=to)
+                .step_by(step as usize)
+                .for_each(|v| values[v as usize] = true);
+        }
+        Ok(Field(values))
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.0.get(value as usize).copied().unwrap_or(false)
+    }
+}
+
+/// `minute hour day-of-month month day-of-week` schedule, days of week are `0`-`7` with
+/// Sunday as `0` or `7`. Like cron, when both day fields are restricted either may match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cron {
+    expression: String,
+    minute: Field,
+    hour: Field,
+    day: Field,
+    month: Field,
+    weekday: Field,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron `{}` needs 5 fields", expression));
+        }
+        let field = |i: usize, min: u32, max: u32| {
+            Field::parse(fields[i], min, max)
+                .map_err(|err| format!("cron `{}`: {}", expression, err))
+        };
+        Ok(Cron {
+            minute: field(0, 0, 59)?,
+            hour: field(1, 0, 23)?,
+            day: field(2, 1, 31)?,
+            month: field(3, 1, 12)?,
+            weekday: field(4, 0, 7)?,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+            expression,
+        })
+    }
+}
+
+impl Display for Cron {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl Cron {
+    pub fn matches(&self, time: &DateTime<Local>) -> bool {
+        let weekday = time.weekday().num_days_from_sunday();
+        let day = self.day.matches(time.day());
+        let weekday = self.weekday.matches(weekday) || (weekday == 0 && self.weekday.matches(7));
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minute.matches(time.minute())
+            && self.hour.matches(time.hour())
+            && self.month.matches(time.month())
+            && day
+    }
+}
+
+/// One `[[schedule.rules]]` entry.
+#[derive(Clone, Deserialize)]
+pub struct ScheduleRule {
+    pub name: String,
+    pub cron: Cron,
+    /// Command as it would be sent to the command topic.
+    pub command: String,
+    /// Do not run on `holidays` of the schedule.
+    #[serde(default = "default_skip_holidays")]
+    pub skip_holidays: bool,
+    /// Dates this rule does not run on.
+    #[serde(default)]
+    pub skip_dates: Vec<NaiveDate>,
+}
+
+/// `[schedule]` commands issued by the gateway itself, in local time.
+#[derive(Clone, Deserialize)]
+pub struct ScheduleConfig {
+    /// Scheduled actions and their outcome are published here.
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub holidays: Vec<NaiveDate>,
+    pub rules: Vec<ScheduleRule>,
+}
+
+/// Scheduled action published to the schedule topic.
+#[derive(Serialize)]
+struct ScheduleReport<'a> {
+    rule: &'a str,
+    command: &'a str,
+    time: String,
+    decision: Decision,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl ScheduleConfig {
+    /// Checks rule names are unique and commands are known, backend commands only with a
+    /// backend that has `controls`.
+    pub fn validate(
+        &self,
+        commands: Option<&CommandsConfig>,
+        controls: bool,
+    ) -> Result<(), String> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if self.rules[..i].iter().any(|r| r.name == rule.name) {
+                return Err(format!(
+                    "schedule rule {} is used more than once",
+                    rule.name
+                ));
+            }
+            match resolve(&rule.command, commands) {
+                (command, _) if command.is_empty() => {
+                    return Err(format!("schedule rule {} has no command", rule.name));
+                }
+                (command, CommandKind::Control) if !controls => {
+                    return Err(format!(
+                        "schedule rule {}: unknown command {}",
+                        rule.name, command
+                    ));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Why `rule` does not run on `date`.
+    fn skipped(&self, rule: &ScheduleRule, date: NaiveDate) -> Option<String> {
+        if rule.skip_dates.contains(&date) {
+            return Some(format!("skip date {}", date));
+        }
+        if rule.skip_holidays && self.holidays.contains(&date) {
+            return Some(format!("holiday {}", date));
+        }
+        None
+    }
+
+    async fn report(&self, publisher: &MqttPublisher, report: &ScheduleReport<'_>) {
+        if let Some(topic) = &self.topic {
+            let payload = serde_json::to_string(report).unwrap();
+            if let Err(err) = publisher.publish(topic.to_string(), payload).await {
+                error!("Error publishing scheduled action: {}", err);
+            }
+        }
+    }
+
+    /// Runs the commands of matching rules at the start of every minute, through the same
+    /// dispatch as MQTT commands, until the gateway shuts down.
+    pub async fn run(&self, panel: Panel, publisher: MqttPublisher, audit: Option<AuditLog>) {
+        for rule in self.rules.iter() {
+            info!("Scheduled {} `{}`: {}", rule.name, rule.cron, rule.command);
+        }
+
+        loop {
+            // a second into the minute, so a timer firing early does not skip it
+            let now = Local::now();
+            let wait = 61 - now.second() as u64;
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+            if is_shutting_down() {
+                info!("Schedule stopped");
+                return;
+            }
+
+            let now = Local::now();
+            for rule in self.rules.iter().filter(|r| r.cron.matches(&now)) {
+                let time = now.to_rfc3339();
+                if let Some(reason) = self.skipped(rule, now.date_naive()) {
+                    info!("Skipping scheduled {}: {}", rule.name, reason);
+                    let report = ScheduleReport {
+                        rule: &rule.name,
+                        command: &rule.command,
+                        time,
+                        decision: Decision::Skipped,
+                        reason: Some(reason),
+                        error: None,
+                    };
+                    self.report(&publisher, &report).await;
+                    continue;
+                }
+
+                let (config, rule) = (self.clone(), rule.clone());
+                let (panel, publisher, audit) = (panel.clone(), publisher.clone(), audit.clone());
+                // drained on shutdown until reported
+                let in_flight = InFlight::track();
+                // commands may take longer than a minute, the next one is not held up
+                tokio::spawn(LogContext::current().scope(async move {
+                    config
+                        .fire(&rule, time, &panel, &publisher, audit.as_ref())
+                        .await;
+                    drop(in_flight);
+                }));
+            }
+        }
+    }
+
+    async fn fire(
+        &self,
+        rule: &ScheduleRule,
+        time: String,
+        panel: &Panel,
+        publisher: &MqttPublisher,
+        audit: Option<&AuditLog>,
+    ) {
+        info!("Running scheduled {}: {}", rule.name, rule.command);
+        // the gateway is trusted with the code of its own commands, only those needing it carry
+        // it as it would end up in the zone of `BYPASS` or the arguments of backend commands
+        let config = panel.commands.as_ref();
+        let (command, _) = resolve(&rule.command, config);
+        let payload = match config.and_then(|c| c.code.as_ref()) {
+            Some(code) if needs_code(&command, config) => format!("{} {}", rule.command, code),
+            _ => rule.command.to_string(),
+        };
+        let source = format!("schedule:{}", rule.name);
+        let received = receive(panel, source, payload);
+        let outcome = execute(panel, received, audit).await;
+
+        if let Some(reply) = outcome.reply() {
+            if let Err(err) = publisher
+                .publish(panel.status_topic.to_string(), reply)
+                .await
+            {
+                error!("Error publishing to mqtt: {}", err);
+            }
+        }
+        let report = ScheduleReport {
+            rule: &rule.name,
+            command: &rule.command,
+            time,
+            decision: outcome.decision,
+            reason: outcome.reason,
+            error: outcome.error,
+        };
+        self.report(publisher, &report).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn values(field: &Field) -> Vec<u32> {
+        (0..field.0.len() as u32)
+            .filter(|v| field.matches(*v))
+            .collect()
+    }
+
+    fn cron(expression: &str) -> Cron {
+        Cron::try_from(expression.to_string()).unwrap()
+    }
+
+    fn time(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let parse = |field| values(&Field::parse(field, 0, 59).unwrap());
+        assert_eq!(parse("5"), vec![5]);
+        assert_eq!(parse("1-3"), vec![1, 2, 3]);
+        assert_eq!(parse("*/15"), vec![0, 15, 30, 45]);
+        assert_eq!(parse("50/5"), vec![50, 55]);
+        assert_eq!(parse("0-30/10"), vec![0, 10, 20, 30]);
+        assert_eq!(parse("1,5-6,*/30"), vec![0, 1, 5, 6, 30]);
+        assert_eq!(
+            values(&Field::parse("*", 1, 12).unwrap()),
+            (1..=12).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_fields() {
+        for field in ["60", "0-60", "5-1", "*/0", "a", "1-", "", "0"] {
+            assert!(Field::parse(field, 1, 59).is_err(), "{}", field);
+        }
+        assert!(Cron::try_from("* * * *".to_string()).is_err());
+        assert!(Cron::try_from("* 24 * * *".to_string()).is_err());
+    }
+
+    #[test]
+    fn matches_times() {
+        // 2026-10-19 is a Monday
+        let weekdays = cron("30 23 * * 1-5");
+        assert!(weekdays.matches(&time(2026, 10, 19, 23, 30)));
+        assert!(!weekdays.matches(&time(2026, 10, 19, 23, 31)));
+        assert!(!weekdays.matches(&time(2026, 10, 19, 22, 30)));
+        assert!(!weekdays.matches(&time(2026, 10, 18, 23, 30)));
+
+        let month = cron("0 12 * 10 *");
+        assert!(month.matches(&time(2026, 10, 1, 12, 0)));
+        assert!(!month.matches(&time(2026, 11, 1, 12, 0)));
+    }
+
+    #[test]
+    fn matches_sunday_as_0_and_7() {
+        // 2026-10-18 is a Sunday
+        assert!(cron("0 8 * * 0").matches(&time(2026, 10, 18, 8, 0)));
+        assert!(cron("0 8 * * 7").matches(&time(2026, 10, 18, 8, 0)));
+        assert!(!cron("0 8 * * 7").matches(&time(2026, 10, 19, 8, 0)));
+    }
+
+    #[test]
+    fn matches_either_restricted_day_field() {
+        // the 1st of the month or any Monday
+        let either = cron("0 8 1 * 1");
+        assert!(either.matches(&time(2026, 10, 1, 8, 0)));
+        assert!(either.matches(&time(2026, 10, 19, 8, 0)));
+        assert!(!either.matches(&time(2026, 10, 20, 8, 0)));
+
+        // only the day of month is restricted
+        let day = cron("0 8 1 * *");
+        assert!(day.matches(&time(2026, 10, 1, 8, 0)));
+        assert!(!day.matches(&time(2026, 10, 19, 8, 0)));
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info};
+use rumqttc::ClientError;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+use crate::backend::panel_backend::PanelBackend;
+use crate::mqtt::mqtt_handler::MqttPublisher;
+use visonic::{Partition, PartitionStatus, State};
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_fast_interval() -> u64 {
+    1
+}
+
+fn default_delay() -> u64 {
+    30
+}
+
+/// `[state]` partition state polling, exit and entry delays are counted down from the
+/// configured panel delays.
+#[derive(Clone, Deserialize)]
+pub struct StateConfig {
+    /// Poll interval outside of delays, capped to half the entry delay so one is not missed.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Poll interval while a partition is in exit or entry delay.
+    #[serde(default = "default_fast_interval")]
+    pub fast_interval: u64,
+    #[serde(default = "default_delay")]
+    pub exit_delay: u64,
+    #[serde(default = "default_delay")]
+    pub entry_delay: u64,
+    /// Publish the expected state as soon as a command is sent, roll back when it fails.
+    #[serde(default)]
+    pub optimistic: bool,
+}
+
+/// Attributes published next to the partition state.
+#[derive(Serialize, PartialEq, Clone)]
+struct Attributes {
+    state: State,
+    status: PartitionStatus,
+    ready: bool,
+    /// Seconds left of the exit or entry delay, approximate as the delay is only seen on the
+    /// first poll after it started.
+    remaining: Option<u64>,
+}
+
+struct Phase {
+    label: &'static str,
+    since: Instant,
+}
+
+/// Home Assistant alarm panel state of a partition, none for states it has no label for.
+fn label(partition: &Partition) -> Option<&'static str> {
+    match (&partition.status, &partition.state) {
+        (PartitionStatus::EXIT_DELAY, _) | (_, State::EXIT_DELAY) => Some("arming"),
+        (PartitionStatus::ENTRY_DELAY, _) | (_, State::ENTRY_DELAY) => Some("pending"),
+        (PartitionStatus::ALARM, _) | (_, State::ALARM) => Some("triggered"),
+        (_, State::AWAY | State::AWAY_INSTANT | State::LATCHKEY) => Some("armed_away"),
+        (_, State::STAY | State::HOME | State::STAY_INSTANT | State::HOME_INSTANT) => {
+            Some("armed_home")
+        }
+        (_, State::NIGHT | State::NIGHT_INSTANT) => Some("armed_night"),
+        (_, State::DISARM) => Some("disarmed"),
+        (_, State::Unknown(_)) => None,
+    }
+}
+
+/// State command of a panel, sent when it is queued and when it ended.
+#[derive(Debug, Clone)]
+pub enum StateCommand {
+    /// A command setting `state` was queued.
+    Started(State),
+    /// The panel processed the command.
+    Completed,
+    /// The command failed or timed out.
+    Failed,
+}
+
+/// What ended a wait for the next poll.
+enum Wake {
+    Interval,
+    Command(StateCommand),
+    /// Some state commands were missed.
+    Lagged,
+}
+
+/// Waits `interval` or for the next state command.
+async fn wait(interval: Duration, commands: &mut broadcast::Receiver<StateCommand>) -> Wake {
+    tokio::select! {
+        _ = tokio::time::sleep(interval) => Wake::Interval,
+        command = commands.recv() => match command {
+            Ok(command) => Wake::Command(command),
+            Err(RecvError::Lagged(_)) => Wake::Lagged,
+            Err(RecvError::Closed) => {
+                tokio::time::sleep(interval).await;
+                Wake::Interval
+            }
+        },
+    }
+}
+
+async fn publish(
+    publisher: &MqttPublisher,
+    state_topic: String,
+    label: &str,
+    attributes: &Attributes,
+) -> Result<(), ClientError> {
+    publisher
+        .publish(state_topic.to_string(), label.to_string())
+        .await?;
+    publish_attributes(publisher, state_topic, attributes).await
+}
+
+async fn publish_attributes(
+    publisher: &MqttPublisher,
+    state_topic: String,
+    attributes: &Attributes,
+) -> Result<(), ClientError> {
+    publisher
+        .publish(
+            format!("{}/attributes", state_topic),
+            serde_json::to_string(attributes).unwrap(),
+        )
+        .await
+}
+
+/// Labels published even over the expected state, the panel reports them whatever command is
+/// waiting.
+fn urgent(label: &str) -> bool {
+    matches!(label, "triggered" | "pending")
+}
+
+impl StateConfig {
+    fn idle_interval(&self) -> u64 {
+        match self.entry_delay {
+            0 => self.interval,
+            delay => self.interval.min(delay / 2).max(1),
+        }
+    }
+
+    fn delay(&self, label: &str) -> Option<u64> {
+        match label {
+            "arming" => Some(self.exit_delay),
+            "pending" => Some(self.entry_delay),
+            _ => None,
+        }
+    }
+
+    /// Label shown for `state` while the command setting it runs.
+    fn expected(&self, state: &State) -> &'static str {
+        match (state, self.exit_delay) {
+            (State::DISARM, _) | (_, 0) => label(&Partition {
+                id: 0,
+                state: state.clone(),
+                status: PartitionStatus::NONE,
+                ready: true,
+            })
+            .unwrap_or("arming"),
+            _ => "arming",
+        }
+    }
+
+    /// Publishes partition states to `<topic>/<partition>` and their attributes to
+    /// `<topic>/<partition>/attributes`, polling fast during exit and entry delays and right
+    /// after a state command. With `optimistic` the expected state is published as soon as a
+    /// command is queued and kept until the last queued one ends, the last confirmed state is
+    /// restored if it failed. Polling goes on meanwhile, alarms and entry delays are published
+    /// right away, other states only update the attributes.
+    pub async fn poll(
+        &self,
+        backend: Arc<dyn PanelBackend>,
+        publisher: MqttPublisher,
+        topic: String,
+        mut commands: broadcast::Receiver<StateCommand>,
+    ) {
+        let mut phases: HashMap<u16, Phase> = HashMap::new();
+        // last confirmed state of every partition
+        let mut published: HashMap<u16, (&'static str, Attributes)> = HashMap::new();
+        // label of the expected state shown instead of the confirmed one
+        let mut expected: Option<&'static str> = None;
+        // state commands started and not yet completed or failed
+        let mut in_flight = 0usize;
+
+        loop {
+            let partitions = match backend.status().await {
+                Ok(status) => status.partitions,
+                Err(err) => {
+                    error!("Failed to poll partition states: {}", err);
+                    vec![]
+                }
+            };
+
+            let mut in_delay = false;
+            let mut failed = false;
+            for partition in partitions.iter() {
+                // a state without label keeps the last one, the raw state is in the attributes
+                let label = match label(partition).or(published.get(&partition.id).map(|p| p.0)) {
+                    Some(label) => label,
+                    None => {
+                        debug!("Partition {} is {}", partition.id, partition.state);
+                        continue;
+                    }
+                };
+                let phase = phases.entry(partition.id).or_insert(Phase {
+                    label,
+                    since: Instant::now(),
+                });
+                if phase.label != label {
+                    info!("Partition {} is {}", partition.id, label);
+                    *phase = Phase {
+                        label,
+                        since: Instant::now(),
+                    };
+                }
+
+                let remaining = self.delay(label).map(|delay| {
+                    in_delay = true;
+                    delay.saturating_sub(phase.since.elapsed().as_secs())
+                });
+                let attributes = Attributes {
+                    state: partition.state.clone(),
+                    status: partition.status.clone(),
+                    ready: partition.ready,
+                    remaining,
+                };
+                let unchanged = published.get(&partition.id).map(|p| &p.1) == Some(&attributes);
+                let state_topic = format!("{}/{}", topic, partition.id);
+
+                // the panel reports the state before the commands until they completed
+                let waiting = in_flight > 0 && !urgent(label);
+                if let Some(shown) = expected.filter(|shown| waiting && *shown != label) {
+                    debug!("Partition {} is {}, showing {}", partition.id, label, shown);
+                    if unchanged {
+                        continue;
+                    }
+                    match publish_attributes(&publisher, state_topic, &attributes).await {
+                        Ok(_) => {
+                            published.insert(partition.id, (label, attributes));
+                        }
+                        Err(err) => error!("Error publishing partition state: {}", err),
+                    }
+                    continue;
+                }
+                if expected.is_none() && unchanged {
+                    continue;
+                }
+
+                match publish(&publisher, state_topic, label, &attributes).await {
+                    Ok(_) => {
+                        published.insert(partition.id, (label, attributes));
+                    }
+                    Err(err) => {
+                        failed = true;
+                        error!("Error publishing partition state: {}", err)
+                    }
+                }
+            }
+            if in_flight == 0 && !partitions.is_empty() && !failed {
+                expected = None;
+            }
+
+            let mut interval = match in_delay {
+                true => self.fast_interval,
+                false => self.idle_interval(),
+            };
+            loop {
+                match wait(Duration::from_secs(interval), &mut commands).await {
+                    Wake::Interval => break,
+                    Wake::Lagged => {
+                        // poll right away, the commands may have ended
+                        in_flight = 0;
+                        break;
+                    }
+                    Wake::Command(StateCommand::Started(state)) => {
+                        in_flight += 1;
+                        interval = self.idle_interval();
+                        if !self.optimistic {
+                            continue;
+                        }
+                        let label = self.expected(&state);
+                        let partition = backend.partition();
+                        for id in published
+                            .keys()
+                            .filter(|id| partition.is_none() || partition == Some(**id))
+                        {
+                            let state_topic = format!("{}/{}", topic, id);
+                            match publisher.publish(state_topic, label.to_string()).await {
+                                Ok(_) => expected = Some(label),
+                                Err(err) => {
+                                    error!("Error publishing expected state: {}", err)
+                                }
+                            }
+                        }
+                    }
+                    Wake::Command(command) => {
+                        in_flight = in_flight.saturating_sub(1);
+                        if expected.is_some() && in_flight > 0 {
+                            continue;
+                        }
+                        if let (StateCommand::Failed, Some(_)) = (command, expected) {
+                            info!("Command failed, restoring partition states");
+                            for (id, (label, attributes)) in published.iter() {
+                                let state_topic = format!("{}/{}", topic, id);
+                                if let Err(err) =
+                                    publish(&publisher, state_topic, label, attributes).await
+                                {
+                                    error!("Error publishing partition state: {}", err);
+                                }
+                            }
+                            expected = None;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use rumqttc::{EventLoop, Request};
+    use visonic::{Device, Event, ResStatus, StateChange, VisonicErr};
+
+    use super::*;
+
+    /// Panel with one partition reporting whatever state the test sets.
+    struct Panel {
+        state: Mutex<(State, PartitionStatus)>,
+    }
+
+    impl Panel {
+        fn set(&self, state: State, status: PartitionStatus) {
+            *self.state.lock().unwrap() = (state, status);
+        }
+    }
+
+    #[async_trait]
+    impl PanelBackend for Panel {
+        fn panel_id(&self) -> String {
+            "test".to_string()
+        }
+
+        async fn status(&self) -> Result<ResStatus, VisonicErr> {
+            let (state, status) = self.state.lock().unwrap().clone();
+            Ok(ResStatus {
+                connected: true,
+                partitions: vec![Partition {
+                    id: 1,
+                    state,
+                    status,
+                    ready: true,
+                }],
+            })
+        }
+
+        async fn set_state(&self, _state: State) -> StateChange {
+            StateChange {
+                process_token: None,
+                process_status: None,
+                error: None,
+            }
+        }
+
+        async fn devices(&self) -> Result<Vec<Device>, VisonicErr> {
+            Ok(vec![])
+        }
+
+        async fn bypass(&self, _zone: u32, _set: bool) -> StateChange {
+            StateChange {
+                process_token: None,
+                process_status: None,
+                error: None,
+            }
+        }
+
+        async fn events(&self) -> Result<Vec<Event>, VisonicErr> {
+            Ok(vec![])
+        }
+
+        async fn troubles(&self) -> Result<String, VisonicErr> {
+            Ok(String::new())
+        }
+
+        async fn alarms(&self) -> Result<String, VisonicErr> {
+            Ok(String::new())
+        }
+
+        async fn alerts(&self) -> Result<String, VisonicErr> {
+            Ok(String::new())
+        }
+
+        async fn panel_info(&self) -> Result<String, VisonicErr> {
+            Ok(String::new())
+        }
+    }
+
+    struct Poller {
+        panel: Arc<Panel>,
+        commands: broadcast::Sender<StateCommand>,
+        published: EventLoop,
+    }
+
+    impl Poller {
+        /// Next label published to the state topic, attributes are skipped.
+        async fn next(&mut self) -> String {
+            loop {
+                if let Request::Publish(publish) = self.published.requests_rx.recv().await.unwrap()
+                {
+                    if publish.topic == "state/1" {
+                        return String::from_utf8(publish.payload.to_vec()).unwrap();
+                    }
+                }
+            }
+        }
+
+        fn send(&self, command: StateCommand) {
+            self.commands.send(command).unwrap();
+        }
+    }
+
+    /// Optimistic poller of a disarmed partition, once its state was first published.
+    async fn start() -> Poller {
+        tokio::time::pause();
+        let panel = Arc::new(Panel {
+            state: Mutex::new((State::DISARM, PartitionStatus::NONE)),
+        });
+        let (publisher, published) = MqttPublisher::detached();
+        let (commands, receiver) = broadcast::channel(16);
+        let config = StateConfig {
+            interval: 10,
+            fast_interval: 1,
+            exit_delay: 30,
+            entry_delay: 30,
+            optimistic: true,
+        };
+        let backend: Arc<dyn PanelBackend> = panel.clone();
+        tokio::spawn(async move {
+            config
+                .poll(backend, publisher, "state".to_string(), receiver)
+                .await
+        });
+
+        let mut poller = Poller {
+            panel,
+            commands,
+            published,
+        };
+        assert_eq!(poller.next().await, "disarmed");
+        poller
+    }
+
+    #[tokio::test]
+    async fn shows_the_expected_state_until_the_command_completed() {
+        let mut poller = start().await;
+
+        poller.send(StateCommand::Started(State::AWAY));
+        assert_eq!(poller.next().await, "arming");
+
+        // the panel still reports the state before the command while it waits in the queue
+        tokio::time::sleep(Duration::from_secs(35)).await;
+        poller.panel.set(State::AWAY, PartitionStatus::NONE);
+        poller.send(StateCommand::Completed);
+        assert_eq!(poller.next().await, "armed_away");
+    }
+
+    #[tokio::test]
+    async fn publishes_alarms_while_a_command_waits() {
+        let mut poller = start().await;
+
+        poller.send(StateCommand::Started(State::AWAY));
+        assert_eq!(poller.next().await, "arming");
+
+        poller.panel.set(State::DISARM, PartitionStatus::ALARM);
+        assert_eq!(poller.next().await, "triggered");
+    }
+
+    #[tokio::test]
+    async fn restores_the_confirmed_state_when_the_last_command_failed() {
+        let mut poller = start().await;
+
+        poller.send(StateCommand::Started(State::AWAY));
+        assert_eq!(poller.next().await, "arming");
+        poller.send(StateCommand::Started(State::HOME));
+        assert_eq!(poller.next().await, "arming");
+
+        // one command is still waiting
+        poller.send(StateCommand::Failed);
+        poller.send(StateCommand::Failed);
+        assert_eq!(poller.next().await, "disarmed");
+    }
+}
@@ -0,0 +1,283 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::visonic::visonic::VisonicErr;
+
+/// Limits applied to every request sent to the cloud for a single panel.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub requests_per_minute: u32,
+    pub burst: u32,
+    /// Consecutive failures opening the circuit.
+    pub failure_threshold: u32,
+    /// Seconds the circuit stays open, doubled every time a retry fails.
+    pub backoff: u64,
+    pub max_backoff: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            requests_per_minute: 60,
+            burst: 10,
+            failure_threshold: 5,
+            backoff: 30,
+            max_backoff: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// While half open a single probe is let through, others fail fast until it completes or
+/// `probe` passes, in case the probe was dropped before it completed.
+enum Circuit {
+    Closed { failures: u32 },
+    Open { until: Instant, backoff: Duration },
+    HalfOpen { backoff: Duration, probe: Instant },
+}
+
+struct GuardState {
+    tokens: f64,
+    refilled: Instant,
+    circuit: Circuit,
+}
+
+/// Rate limiter and circuit breaker shared by every clone of a `Visonic`.
+#[derive(Clone)]
+pub struct Guard {
+    state: Arc<Mutex<GuardState>>,
+    circuit: Arc<watch::Sender<CircuitState>>,
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(CircuitState::Closed);
+        Guard {
+            state: Arc::new(Mutex::new(GuardState {
+                // capped to the configured burst on first acquire
+                tokens: f64::MAX,
+                refilled: Instant::now(),
+                circuit: Circuit::Closed { failures: 0 },
+            })),
+            circuit: Arc::new(tx),
+        }
+    }
+}
+
+impl Guard {
+    pub fn subscribe(&self) -> watch::Receiver<CircuitState> {
+        self.circuit.subscribe()
+    }
+
+    fn report(&self, circuit: &Circuit) {
+        let state = match circuit {
+            Circuit::Closed { .. } => CircuitState::Closed,
+            Circuit::Open { .. } => CircuitState::Open,
+            Circuit::HalfOpen { .. } => CircuitState::HalfOpen,
+        };
+        if *self.circuit.borrow() != state {
+            self.circuit.send_replace(state);
+        }
+    }
+
+    /// Waits for a free request slot, fails fast while the circuit is open or a half open probe
+    /// is in flight.
+    pub async fn acquire(&self, limits: &Limits) -> Result<(), VisonicErr> {
+        let rate = limits.requests_per_minute.max(1) as f64 / 60.0;
+        let burst = limits.burst.max(1) as f64;
+
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+
+                match state.circuit {
+                    Circuit::Open { until, .. } if now < until => {
+                        return Err(VisonicErr::CircuitOpen((until - now).as_secs()));
+                    }
+                    Circuit::HalfOpen { probe, .. } if now < probe => {
+                        return Err(VisonicErr::CircuitOpen((probe - now).as_secs()));
+                    }
+                    Circuit::Open { backoff, .. } | Circuit::HalfOpen { backoff, .. } => {
+                        info!("Visonic circuit half open, retrying");
+                        state.circuit = Circuit::HalfOpen {
+                            backoff,
+                            probe: now + backoff,
+                        };
+                        self.report(&state.circuit);
+                    }
+                    _ => (),
+                }
+
+                let elapsed = now.duration_since(state.refilled).as_secs_f64();
+                state.tokens = (state.tokens + elapsed * rate).min(burst);
+                state.refilled = now;
+
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    return Ok(());
+                }
+                Duration::from_secs_f64((1.0 - state.tokens) / rate)
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(state.circuit, Circuit::Closed { .. }) {
+            info!("Visonic circuit closed");
+        }
+        state.circuit = Circuit::Closed { failures: 0 };
+        self.report(&state.circuit);
+    }
+
+    pub fn failure(&self, limits: &Limits) {
+        let mut state = self.state.lock().unwrap();
+        let initial = Duration::from_secs(limits.backoff);
+        let open = |backoff: Duration| {
+            warn!("Visonic circuit open for {}s", backoff.as_secs());
+            Circuit::Open {
+                until: Instant::now() + backoff,
+                backoff,
+            }
+        };
+
+        state.circuit = match state.circuit {
+            Circuit::Closed { failures } if failures + 1 >= limits.failure_threshold => {
+                open(initial)
+            }
+            Circuit::Closed { failures } => Circuit::Closed {
+                failures: failures + 1,
+            },
+            Circuit::HalfOpen { backoff, .. } => {
+                open((backoff * 2).min(Duration::from_secs(limits.max_backoff)))
+            }
+            Circuit::Open { until, backoff } => Circuit::Open { until, backoff },
+        };
+        self.report(&state.circuit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            requests_per_minute: 60,
+            burst: 2,
+            failure_threshold: 2,
+            backoff: 10,
+            max_backoff: 30,
+        }
+    }
+
+    fn state(guard: &Guard) -> CircuitState {
+        *guard.subscribe().borrow()
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_slot_once_the_burst_is_used() {
+        tokio::time::pause();
+        let (guard, limits) = (Guard::default(), limits());
+        let start = Instant::now();
+
+        guard.acquire(&limits).await.unwrap();
+        guard.acquire(&limits).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // one request per second refills the bucket
+        guard.acquire(&limits).await.unwrap();
+        assert_eq!(start.elapsed().as_secs(), 1);
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() {
+        tokio::time::pause();
+        let (guard, limits) = (Guard::default(), limits());
+
+        guard.failure(&limits);
+        guard.success();
+        guard.failure(&limits);
+        assert_eq!(state(&guard), CircuitState::Closed);
+
+        guard.failure(&limits);
+        assert_eq!(state(&guard), CircuitState::Open);
+        assert!(matches!(
+            guard.acquire(&limits).await,
+            Err(VisonicErr::CircuitOpen(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lets_a_single_probe_through_when_half_open() {
+        tokio::time::pause();
+        let (guard, limits) = (Guard::default(), limits());
+        guard.failure(&limits);
+        guard.failure(&limits);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        guard.acquire(&limits).await.unwrap();
+        assert_eq!(state(&guard), CircuitState::HalfOpen);
+        assert!(matches!(
+            guard.acquire(&limits).await,
+            Err(VisonicErr::CircuitOpen(_))
+        ));
+
+        guard.success();
+        assert_eq!(state(&guard), CircuitState::Closed);
+        guard.acquire(&limits).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn doubles_the_backoff_when_the_probe_fails() {
+        tokio::time::pause();
+        let (guard, limits) = (Guard::default(), limits());
+        guard.failure(&limits);
+        guard.failure(&limits);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        guard.acquire(&limits).await.unwrap();
+        guard.failure(&limits);
+        assert_eq!(state(&guard), CircuitState::Open);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(guard.acquire(&limits).await.is_err());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        guard.acquire(&limits).await.unwrap();
+        assert_eq!(state(&guard), CircuitState::HalfOpen);
+
+        // capped to max_backoff
+        guard.failure(&limits);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        guard.acquire(&limits).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_a_probe_that_never_completed() {
+        tokio::time::pause();
+        let (guard, limits) = (Guard::default(), limits());
+        guard.failure(&limits);
+        guard.failure(&limits);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        guard.acquire(&limits).await.unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        guard.acquire(&limits).await.unwrap();
+        assert_eq!(state(&guard), CircuitState::HalfOpen);
+    }
+}
@@ -9,6 +9,7 @@ pub(crate) const RES_AUTH: &str = "/auth";
 pub(crate) const RES_STATUS: &str = "/status";
 pub(crate) const RES_VERSIONS: &str = "/version";
 pub(crate) const RES_SET_STATE: &str = "/set_state";
+pub(crate) const RES_SET_BYPASS_ZONE: &str = "/set_bypass_zone";
 pub(crate) const RES_PROCESS_STATUS: &str = "/process_status";
 pub(crate) const RES_EVENTS: &str = "/events";
 pub(crate) const RES_ALARMS: &str = "/alarms";
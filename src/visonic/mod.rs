@@ -1,3 +1,8 @@
+#[allow(clippy::module_inception)]
 pub mod visonic;
+
 mod main;
-use main::*;
\ No newline at end of file
+use main::*;
+
+pub mod guard;
+pub mod watcher;
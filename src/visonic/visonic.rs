@@ -2,10 +2,13 @@ use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::time::Duration;
 
+use reqwest::{RequestBuilder, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 use tokio::time::Instant;
 
+use crate::visonic::guard::{CircuitState, Guard, Limits};
 use crate::visonic::*;
 
 #[derive(Clone, Deserialize)]
@@ -17,6 +20,10 @@ pub struct Visonic {
     pub user_email: String,
     pub user_password: String,
     pub panel_id: String,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(skip)]
+    pub(crate) guard: Guard,
 }
 
 #[derive(Clone)]
@@ -61,6 +68,9 @@ pub enum VisonicErr {
     VersionNotSupported(String),
     HttpError(u16, String),
     RetriesExhausted,
+    CircuitOpen(u64),
+    Backend(String),
+    Unsupported(String),
 }
 
 impl From<reqwest::Error> for VisonicErr {
@@ -79,14 +89,101 @@ impl Display for VisonicErr {
             }
             VisonicErr::HttpError(code, s) => write!(f, "VisonicErr::HttpError({}, {})", code, s),
             VisonicErr::RetriesExhausted => write!(f, "VisonicErr::RetriesExhausted"),
+            VisonicErr::CircuitOpen(secs) => write!(f, "VisonicErr::CircuitOpen({}s)", secs),
+            VisonicErr::Backend(msg) => write!(f, "VisonicErr::Backend({})", msg),
+            VisonicErr::Unsupported(msg) => write!(f, "VisonicErr::Unsupported({})", msg),
         }
     }
 }
 
 impl Visonic {
+    /// Client of a single panel with default rate limits.
+    pub fn new(
+        hostname: &str,
+        user_code: &str,
+        app_id: &str,
+        partition: i8,
+        user_email: &str,
+        user_password: &str,
+        panel_id: &str,
+    ) -> Visonic {
+        Visonic {
+            hostname: hostname.to_string(),
+            user_code: user_code.to_string(),
+            app_id: app_id.to_string(),
+            partition,
+            user_email: user_email.to_string(),
+            user_password: user_password.to_string(),
+            panel_id: panel_id.to_string(),
+            limits: Limits::default(),
+            guard: Guard::default(),
+        }
+    }
+
+    pub fn with_limits(self, limits: Limits) -> Visonic {
+        Visonic { limits, ..self }
+    }
+
+    /// Circuit breaker state of the client, changes as requests fail and recover.
+    pub fn circuit(&self) -> watch::Receiver<CircuitState> {
+        self.guard.subscribe()
+    }
+
+    /// Sends every cloud request through the shared rate limiter and circuit breaker.
+    pub(crate) async fn send(&self, req: RequestBuilder) -> Result<Response, VisonicErr> {
+        let res = self.attempt(req).await?;
+        if !failed(res.status()) {
+            self.guard.success();
+        }
+        Ok(res)
+    }
+
+    /// Like `send` without counting the response as success yet.
+    async fn attempt(&self, req: RequestBuilder) -> Result<Response, VisonicErr> {
+        self.guard.acquire(&self.limits).await?;
+
+        match req.send().await {
+            Ok(res) => {
+                if failed(res.status()) {
+                    self.guard.failure(&self.limits);
+                }
+                Ok(res)
+            }
+            Err(err) => {
+                self.guard.failure(&self.limits);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Sends a login request, a response that can not be decoded fails like refused
+    /// credentials so a wrong login backs off instead of being retried on every call.
+    async fn login_json<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T, VisonicErr> {
+        let res = self.attempt(req).await?;
+        let status = res.status();
+        if failed(status) {
+            let body = res.text().await.unwrap_or_default();
+            return Err(VisonicErr::HttpError(status.as_u16(), body));
+        }
+        match res.json::<T>().await {
+            Ok(body) => {
+                self.guard.success();
+                Ok(body)
+            }
+            Err(err) => {
+                self.guard.failure(&self.limits);
+                Err(err.into())
+            }
+        }
+    }
+
     async fn version(&self) -> Result<RespVersion, VisonicErr> {
         let ep = format!("https://{}/rest_api{}", &self.hostname, RES_VERSIONS);
-        let res: RespVersion = reqwest::get(ep).await?.json().await?;
+        let res: RespVersion = self
+            .send(reqwest::Client::new().get(ep))
+            .await?
+            .json()
+            .await?;
 
         Ok(res)
     }
@@ -118,16 +215,13 @@ impl Visonic {
             panel_serial: self.panel_id.to_string(),
         };
 
-        let resp: ResPanelLogin = reqwest::Client::new()
-            .post(uri(&self.hostname, RES_PANEL_LOGIN))
-            .header("User-Token", user_code)
-            .json(&req)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        Ok(resp)
+        self.login_json(
+            reqwest::Client::new()
+                .post(uri(&self.hostname, RES_PANEL_LOGIN))
+                .header("User-Token", user_code)
+                .json(&req),
+        )
+        .await
     }
     async fn account_login(&self) -> Result<RespLogin, VisonicErr> {
         let req = ReqLogin {
@@ -136,15 +230,12 @@ impl Visonic {
             app_id: self.app_id.to_string(),
         };
 
-        let resp: RespLogin = reqwest::Client::new()
-            .post(uri(&self.hostname, RES_AUTH))
-            .json(&req)
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        Ok(resp)
+        self.login_json(
+            reqwest::Client::new()
+                .post(uri(&self.hostname, RES_AUTH))
+                .json(&req),
+        )
+        .await
     }
 
     pub async fn login(&self) -> Result<AuthedVisonic, VisonicErr> {
@@ -160,15 +251,24 @@ impl Visonic {
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// Responses counted as failures by the circuit breaker, refused credentials included as
+/// retrying them risks locking the account.
+fn failed(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::FORBIDDEN
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Partition {
     pub id: u16,
     pub state: State,
-    pub status: String,
+    pub status: PartitionStatus,
     pub ready: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct ResStatus {
     pub connected: bool,
     pub partitions: Vec<Partition>,
@@ -180,27 +280,306 @@ struct ReqSetState {
     state: State,
 }
 
+#[derive(Serialize)]
+struct ReqSetBypassZone {
+    zone: u32,
+    set: bool,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct ResProcessToken {
     pub process_token: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ResProcessStatus {
     pub token: String,
     pub status: String,
     pub error: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+/// Outcome of a `set_state` or `set_bypass_zone` request, `process_token` is set once the
+/// panel accepted it.
+#[derive(Debug)]
+pub struct StateChange {
+    pub process_token: Option<String>,
+    pub process_status: Option<ResProcessStatus>,
+    pub error: Option<VisonicErr>,
+}
+
+impl StateChange {
+    /// Outcome without the process details.
+    pub fn result(self) -> Result<(), VisonicErr> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Panel models as reported in `panel_info`, upper case with `_` for spaces and dashes.
+const INSTANT_MODELS: [&str; 11] = [
+    "POWERMASTER",
+    "POWERMASTER_10",
+    "POWERMASTER_30",
+    "POWERMASTER_33",
+    "POWERMAX",
+    "POWERMAX_PLUS",
+    "POWERMAX_PRO",
+    "POWERMAX_PRO_PART",
+    "POWERMAX_COMPLETE",
+    "POWERMAX_COMPLETE_PART",
+    "POWERMAX_EXPRESS",
+];
+const LATCHKEY_MODELS: [&str; 6] = [
+    "POWERMASTER",
+    "POWERMASTER_10",
+    "POWERMASTER_30",
+    "POWERMASTER_33",
+    "POWERMAX_PRO",
+    "POWERMAX_COMPLETE",
+];
+
+/// `model` of a `panel_info` response, if reported.
+pub fn panel_model(panel_info: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(panel_info)
+        .ok()?
+        .get("model")?
+        .as_str()
+        .map(|model| model.to_string())
+}
+
+/// Partition state as named by the panel, states not known here are kept as `Unknown`.
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
 pub enum State {
     AWAY,
     DISARM,
     STAY,
     NIGHT,
+    HOME,
+    AWAY_INSTANT,
+    STAY_INSTANT,
+    HOME_INSTANT,
+    NIGHT_INSTANT,
+    LATCHKEY,
+    EXIT_DELAY,
+    ENTRY_DELAY,
+    ALARM,
+    Unknown(String),
+}
+
+impl State {
+    pub fn as_str(&self) -> &str {
+        match self {
+            State::AWAY => "AWAY",
+            State::DISARM => "DISARM",
+            State::STAY => "STAY",
+            State::NIGHT => "NIGHT",
+            State::HOME => "HOME",
+            State::AWAY_INSTANT => "AWAY_INSTANT",
+            State::STAY_INSTANT => "STAY_INSTANT",
+            State::HOME_INSTANT => "HOME_INSTANT",
+            State::NIGHT_INSTANT => "NIGHT_INSTANT",
+            State::LATCHKEY => "LATCHKEY",
+            State::EXIT_DELAY => "EXIT_DELAY",
+            State::ENTRY_DELAY => "ENTRY_DELAY",
+            State::ALARM => "ALARM",
+            State::Unknown(state) => state,
+        }
+    }
+
+    /// Whether panel `model` (as in `panel_info`) can arm in this state, instant and latchkey
+    /// modes are limited to some models.
+    pub fn supported_by(&self, model: &str) -> bool {
+        let model = model.to_uppercase().replace([' ', '-'], "_");
+        let models: &[&str] = match self {
+            State::AWAY_INSTANT
+            | State::STAY_INSTANT
+            | State::HOME_INSTANT
+            | State::NIGHT_INSTANT => &INSTANT_MODELS,
+            State::LATCHKEY => &LATCHKEY_MODELS,
+            _ => return true,
+        };
+        models.contains(&model.as_str())
+    }
+
+    /// States that can be requested with `set_state`, the others are only reported.
+    pub fn can_set(&self) -> bool {
+        !matches!(
+            self,
+            State::EXIT_DELAY | State::ENTRY_DELAY | State::ALARM | State::Unknown(_)
+        )
+    }
+}
+
+impl From<String> for State {
+    fn from(state: String) -> Self {
+        match state.as_str() {
+            "AWAY" => State::AWAY,
+            "DISARM" => State::DISARM,
+            "STAY" => State::STAY,
+            "NIGHT" => State::NIGHT,
+            "HOME" => State::HOME,
+            "AWAY_INSTANT" => State::AWAY_INSTANT,
+            "STAY_INSTANT" => State::STAY_INSTANT,
+            "HOME_INSTANT" => State::HOME_INSTANT,
+            "NIGHT_INSTANT" => State::NIGHT_INSTANT,
+            "LATCHKEY" => State::LATCHKEY,
+            "EXIT_DELAY" => State::EXIT_DELAY,
+            "ENTRY_DELAY" => State::ENTRY_DELAY,
+            "ALARM" => State::ALARM,
+            _ => State::Unknown(state),
+        }
+    }
+}
+
+impl From<State> for String {
+    fn from(state: State) -> Self {
+        state.as_str().to_string()
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// What a partition is doing besides its state, empty `NONE` when idle.
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum PartitionStatus {
+    NONE,
+    EXIT_DELAY,
+    ENTRY_DELAY,
+    ALARM,
+    USER_TEST,
+    DOWNLOADING,
+    PROGRAMMING,
+    INSTALLER,
+    Unknown(String),
+}
+
+impl PartitionStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            PartitionStatus::NONE => "",
+            PartitionStatus::EXIT_DELAY => "EXIT_DELAY",
+            PartitionStatus::ENTRY_DELAY => "ENTRY_DELAY",
+            PartitionStatus::ALARM => "ALARM",
+            PartitionStatus::USER_TEST => "USER_TEST",
+            PartitionStatus::DOWNLOADING => "DOWNLOADING",
+            PartitionStatus::PROGRAMMING => "PROGRAMMING",
+            PartitionStatus::INSTALLER => "INSTALLER",
+            PartitionStatus::Unknown(status) => status,
+        }
+    }
+}
+
+impl From<String> for PartitionStatus {
+    fn from(status: String) -> Self {
+        match status.as_str() {
+            "" => PartitionStatus::NONE,
+            "EXIT_DELAY" => PartitionStatus::EXIT_DELAY,
+            "ENTRY_DELAY" => PartitionStatus::ENTRY_DELAY,
+            "ALARM" => PartitionStatus::ALARM,
+            "USER_TEST" => PartitionStatus::USER_TEST,
+            "DOWNLOADING" => PartitionStatus::DOWNLOADING,
+            "PROGRAMMING" => PartitionStatus::PROGRAMMING,
+            "INSTALLER" => PartitionStatus::INSTALLER,
+            _ => PartitionStatus::Unknown(status),
+        }
+    }
+}
+
+impl From<PartitionStatus> for String {
+    fn from(status: PartitionStatus) -> Self {
+        status.as_str().to_string()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DeviceWarning {
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Device {
+    pub id: u64,
+    pub zone: Option<u32>,
+    pub location: Option<String>,
+    pub device_type: Option<String>,
+    pub subtype: Option<String>,
+    #[serde(default)]
+    pub partitions: Vec<i16>,
+    #[serde(default)]
+    pub bypass: bool,
+    #[serde(default)]
+    pub warnings: Vec<DeviceWarning>,
+}
+
+impl Device {
+    /// Zones can be bypassed, other devices (keyfobs, sirens..) can not.
+    pub fn is_zone(&self) -> bool {
+        self.zone.is_some() && self.device_type.as_deref().is_some_and(|t| t.eq("ZONE"))
+    }
+
+    /// Open or tampered zone, keeps its partition from being ready.
+    pub fn is_faulted(&self) -> bool {
+        self.warnings
+            .iter()
+            .any(|w| w.kind.eq("OPENED") || w.kind.starts_with("TAMPER"))
+    }
+
+    /// Zone number followed by location, as shown to users.
+    pub fn label(&self) -> String {
+        match (self.zone, &self.location) {
+            (Some(zone), Some(location)) => format!("{} {}", zone, location),
+            (Some(zone), None) => zone.to_string(),
+            (None, Some(location)) => location.to_string(),
+            (None, None) => self.id.to_string(),
+        }
+    }
+
+    /// Matches zone number or, case insensitive, location name.
+    pub fn matches(&self, target: &str) -> bool {
+        self.zone.is_some_and(|z| z.to_string().eq(target))
+            || self
+                .location
+                .as_ref()
+                .is_some_and(|l| l.eq_ignore_ascii_case(target))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Event {
+    pub event: u64,
+    pub type_id: Option<u32>,
+    pub label: Option<String>,
+    pub description: Option<String>,
+    pub appointment: Option<String>,
+    pub datetime: Option<String>,
+    pub device_type: Option<String>,
+    pub zone: Option<u32>,
+    #[serde(default)]
+    pub partitions: Vec<i16>,
 }
 
 impl AuthedVisonic {
+    /// `User-Token` header of the session, for callers keeping it out of their logs.
+    pub fn user_token(&self) -> &str {
+        &self.user_token
+    }
+
+    /// `Session-Token` header of the session, for callers keeping it out of their logs.
+    pub fn session_token(&self) -> &str {
+        &self.session_token
+    }
+
     pub async fn status(&self) -> Result<ResStatus, VisonicErr> {
         self.get_json::<ResStatus>(RES_STATUS).await
     }
@@ -210,27 +589,164 @@ impl AuthedVisonic {
     }
 
     pub async fn arm(&self) -> Result<(), VisonicErr> {
-        let res = self.set_state(State::AWAY).await?;
-        let _ = self.process_set_state(res).await?;
-        Ok(())
+        self.arm_change().await.result()
     }
 
     pub async fn disarm(&self) -> Result<(), VisonicErr> {
-        let res = self.set_state(State::DISARM).await?;
-        let _ = self.process_set_state(res).await?;
-        Ok(())
+        self.disarm_change().await.result()
     }
 
     pub async fn arm_night(&self) -> Result<(), VisonicErr> {
-        let res = self.set_state(State::NIGHT).await?;
-        let _ = self.process_set_state(res).await?;
-        Ok(())
+        self.arm_night_change().await.result()
     }
 
     pub async fn arm_stay(&self) -> Result<(), VisonicErr> {
-        let res = self.set_state(State::STAY).await?;
-        let _ = self.process_set_state(res).await?;
-        Ok(())
+        self.arm_stay_change().await.result()
+    }
+
+    /// Like [`AuthedVisonic::arm`], reporting the panel process.
+    pub async fn arm_change(&self) -> StateChange {
+        self.change_state(State::AWAY).await
+    }
+
+    /// Like [`AuthedVisonic::disarm`], reporting the panel process.
+    pub async fn disarm_change(&self) -> StateChange {
+        self.change_state(State::DISARM).await
+    }
+
+    /// Like [`AuthedVisonic::arm_night`], reporting the panel process.
+    pub async fn arm_night_change(&self) -> StateChange {
+        self.change_state(State::NIGHT).await
+    }
+
+    /// Like [`AuthedVisonic::arm_stay`], reporting the panel process.
+    pub async fn arm_stay_change(&self) -> StateChange {
+        self.change_state(State::STAY).await
+    }
+
+    /// Arms away without entry delay.
+    pub async fn arm_instant(&self) -> Result<(), VisonicErr> {
+        self.arm_instant_change().await.result()
+    }
+
+    /// Arms home without entry delay.
+    pub async fn arm_stay_instant(&self) -> Result<(), VisonicErr> {
+        self.arm_stay_instant_change().await.result()
+    }
+
+    /// Arms night without entry delay.
+    pub async fn arm_night_instant(&self) -> Result<(), VisonicErr> {
+        self.arm_night_instant_change().await.result()
+    }
+
+    /// Arms away and notifies when a latchkey user disarms.
+    pub async fn arm_latchkey(&self) -> Result<(), VisonicErr> {
+        self.arm_latchkey_change().await.result()
+    }
+
+    /// Like [`AuthedVisonic::arm_instant`], reporting the panel process.
+    pub async fn arm_instant_change(&self) -> StateChange {
+        self.change_supported_state(State::AWAY_INSTANT).await
+    }
+
+    /// Like [`AuthedVisonic::arm_stay_instant`], reporting the panel process.
+    pub async fn arm_stay_instant_change(&self) -> StateChange {
+        self.change_supported_state(State::STAY_INSTANT).await
+    }
+
+    /// Like [`AuthedVisonic::arm_night_instant`], reporting the panel process.
+    pub async fn arm_night_instant_change(&self) -> StateChange {
+        self.change_supported_state(State::NIGHT_INSTANT).await
+    }
+
+    /// Like [`AuthedVisonic::arm_latchkey`], reporting the panel process.
+    pub async fn arm_latchkey_change(&self) -> StateChange {
+        self.change_supported_state(State::LATCHKEY).await
+    }
+
+    /// Checks `state` against the panel model before requesting it, refused when the model can
+    /// not be read and let through when the panel does not report one, like the gateway.
+    async fn change_supported_state(&self, state: State) -> StateChange {
+        let unsupported = match self.panel_info().await {
+            Ok(panel_info) => panel_model(&panel_info)
+                .filter(|model| !state.supported_by(model))
+                .map(|model| VisonicErr::Unsupported(format!("{} on {}", state, model))),
+            Err(err) => Some(err),
+        };
+        match unsupported {
+            Some(err) => StateChange {
+                process_token: None,
+                process_status: None,
+                error: Some(err),
+            },
+            None => self.change_state(state).await,
+        }
+    }
+
+    /// Requests `state` and waits for the panel to process it. Readiness is not checked, a not
+    /// ready panel refuses or fails the process on its own.
+    pub async fn change_state(&self, state: State) -> StateChange {
+        self.run_process(self.set_state(state).await).await
+    }
+
+    /// Bypasses (`set`) or restores `zone` and waits for the panel to process it.
+    pub async fn bypass_zone(&self, zone: u32, set: bool) -> StateChange {
+        self.run_process(self.set_bypass_zone(zone, set).await)
+            .await
+    }
+
+    async fn run_process(&self, token: Result<ResProcessToken, VisonicErr>) -> StateChange {
+        let token = match token {
+            Ok(token) => token,
+            Err(err) => {
+                return StateChange {
+                    process_token: None,
+                    process_status: None,
+                    error: Some(err),
+                }
+            }
+        };
+
+        match self.process_set_state(token.clone()).await {
+            Ok(statuses) => StateChange {
+                process_token: Some(token.process_token),
+                process_status: statuses.into_iter().find(|s| s.status.eq("succeeded")),
+                error: None,
+            },
+            Err(err) => {
+                // last known status of the failed process
+                let status = self
+                    .process_status_once(token.clone())
+                    .await
+                    .ok()
+                    .and_then(|statuses| statuses.into_iter().next());
+                StateChange {
+                    process_token: Some(token.process_token),
+                    process_status: status,
+                    error: Some(err),
+                }
+            }
+        }
+    }
+
+    async fn set_bypass_zone(&self, zone: u32, set: bool) -> Result<ResProcessToken, VisonicErr> {
+        let req = ReqSetBypassZone { zone, set };
+        let res = self
+            .visonic
+            .send(
+                reqwest::Client::new()
+                    .post(uri(&self.visonic.hostname, RES_SET_BYPASS_ZONE))
+                    .json(&req)
+                    .with_user_session_token(
+                        self.user_token.to_string(),
+                        self.session_token.to_string(),
+                    ),
+            )
+            .await?
+            .json()
+            .await?;
+
+        Ok(res)
     }
 
     async fn set_state(&self, state: State) -> Result<ResProcessToken, VisonicErr> {
@@ -238,11 +754,17 @@ impl AuthedVisonic {
             partition: -1,
             state,
         };
-        let res = reqwest::Client::new()
-            .post(uri(&self.visonic.hostname, RES_SET_STATE))
-            .json(&req)
-            .with_user_session_token(self.user_token.to_string(), self.session_token.to_string())
-            .send()
+        let res = self
+            .visonic
+            .send(
+                reqwest::Client::new()
+                    .post(uri(&self.visonic.hostname, RES_SET_STATE))
+                    .json(&req)
+                    .with_user_session_token(
+                        self.user_token.to_string(),
+                        self.session_token.to_string(),
+                    ),
+            )
             .await?
             .json()
             .await?;
@@ -276,10 +798,12 @@ impl AuthedVisonic {
             token.process_token
         );
 
-        let res: Vec<ResProcessStatus> = reqwest::Client::new()
-            .get(url)
-            .with_user_session_token(self.user_token.to_string(), self.session_token.to_string())
-            .send()
+        let res: Vec<ResProcessStatus> = self
+            .visonic
+            .send(reqwest::Client::new().get(url).with_user_session_token(
+                self.user_token.to_string(),
+                self.session_token.to_string(),
+            ))
             .await?
             .json()
             .await?;
@@ -318,9 +842,8 @@ impl AuthedVisonic {
         Err(VisonicErr::RetriesExhausted)
     }
 
-    //TODO: NEED SAMPLE
-    pub async fn events(&self) -> Result<String, VisonicErr> {
-        self.get_text(RES_EVENTS).await
+    pub async fn events(&self) -> Result<Vec<Event>, VisonicErr> {
+        self.get_json::<Vec<Event>>(RES_EVENTS).await
     }
 
     //TODO: NEED SAMPLE
@@ -347,8 +870,8 @@ impl AuthedVisonic {
         self.get_text(RES_WAKEUP_SMS).await
     }
 
-    pub async fn devices(&self) -> Result<String, VisonicErr> {
-        self.get_text(RES_DEVICES).await
+    pub async fn devices(&self) -> Result<Vec<Device>, VisonicErr> {
+        self.get_json::<Vec<Device>>(RES_DEVICES).await
     }
 
     pub async fn locations(&self) -> Result<String, VisonicErr> {
@@ -356,10 +879,16 @@ impl AuthedVisonic {
     }
 
     async fn get_text(&self, endpoint: &str) -> Result<String, VisonicErr> {
-        let s: String = reqwest::Client::new()
-            .get(uri(&self.visonic.hostname, endpoint))
-            .with_user_session_token(self.user_token.to_string(), self.session_token.to_string())
-            .send()
+        let s: String = self
+            .visonic
+            .send(
+                reqwest::Client::new()
+                    .get(uri(&self.visonic.hostname, endpoint))
+                    .with_user_session_token(
+                        self.user_token.to_string(),
+                        self.session_token.to_string(),
+                    ),
+            )
             .await?
             .text()
             .await?;
@@ -368,10 +897,16 @@ impl AuthedVisonic {
     }
 
     async fn get_json<R: DeserializeOwned>(&self, endpoint: &str) -> Result<R, VisonicErr> {
-        let res: R = reqwest::Client::new()
-            .get(uri(&self.visonic.hostname, endpoint))
-            .with_user_session_token(self.user_token.to_string(), self.session_token.to_string())
-            .send()
+        let res: R = self
+            .visonic
+            .send(
+                reqwest::Client::new()
+                    .get(uri(&self.visonic.hostname, endpoint))
+                    .with_user_session_token(
+                        self.user_token.to_string(),
+                        self.session_token.to_string(),
+                    ),
+            )
             .await?
             .json()
             .await?;
@@ -379,3 +914,20 @@ impl AuthedVisonic {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_exact_panel_models() {
+        assert!(State::NIGHT_INSTANT.supported_by("PowerMaster-10"));
+        assert!(State::AWAY_INSTANT.supported_by("PowerMax Express"));
+        assert!(State::LATCHKEY.supported_by("POWERMAX_PRO"));
+        assert!(!State::LATCHKEY.supported_by("PowerMax Pro Part"));
+        assert!(!State::LATCHKEY.supported_by("POWERMAX"));
+        assert!(!State::STAY_INSTANT.supported_by("POWERMAXIMUM"));
+        assert!(!State::AWAY_INSTANT.supported_by(""));
+        assert!(State::AWAY.supported_by(""));
+    }
+}
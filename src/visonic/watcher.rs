@@ -0,0 +1,365 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::visonic::visonic::{AuthedVisonic, Event, ResStatus, State, Visonic, VisonicErr};
+
+/// Poll intervals of [`Visonic::watch`] in seconds.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct WatchIntervals {
+    /// Partition states, readiness and connectivity.
+    pub status: u64,
+    pub events: u64,
+    pub troubles: u64,
+    /// Session age after which the watcher logs in again.
+    pub session: u64,
+}
+
+impl Default for WatchIntervals {
+    fn default() -> Self {
+        WatchIntervals {
+            status: 10,
+            events: 60,
+            troubles: 60,
+            session: 600,
+        }
+    }
+}
+
+/// Change reported by [`Visonic::watch`].
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum PanelChange {
+    /// Partition state, `from` is `None` for the first status seen.
+    State {
+        partition: u16,
+        from: Option<State>,
+        to: State,
+    },
+    Ready {
+        partition: u16,
+        ready: bool,
+    },
+    Connectivity {
+        connected: bool,
+    },
+    Event(Event),
+    TroubleRaised(Value),
+    TroubleCleared(Value),
+}
+
+struct Watcher {
+    visonic: Visonic,
+    intervals: WatchIntervals,
+    session: Option<(AuthedVisonic, Instant)>,
+    pending: VecDeque<Result<PanelChange, VisonicErr>>,
+    partitions: HashMap<u16, (State, bool)>,
+    connected: Option<bool>,
+    last_event: Option<u64>,
+    troubles: Option<Vec<Value>>,
+    next_status: Instant,
+    next_events: Instant,
+    next_troubles: Instant,
+}
+
+// a body that is not a list fails the poll, an empty list would clear every trouble
+fn parse_list(s: &str) -> Result<Vec<Value>, VisonicErr> {
+    match serde_json::from_str::<Value>(s) {
+        Ok(Value::Array(items)) => Ok(items),
+        Ok(Value::Null) => Ok(vec![]),
+        Ok(error) if error.get("error").is_some() => {
+            Err(VisonicErr::Backend(format!("error response {}", error)))
+        }
+        Ok(other) => Ok(vec![other]),
+        Err(err) => Err(VisonicErr::Backend(format!(
+            "malformed response {}: {}",
+            s, err
+        ))),
+    }
+}
+
+impl Watcher {
+    fn new(visonic: Visonic, intervals: WatchIntervals) -> Watcher {
+        let now = Instant::now();
+        Watcher {
+            visonic,
+            intervals,
+            session: None,
+            pending: VecDeque::new(),
+            partitions: HashMap::new(),
+            connected: None,
+            last_event: None,
+            troubles: None,
+            next_status: now,
+            next_events: now,
+            next_troubles: now,
+        }
+    }
+
+    async fn session(&mut self) -> Result<AuthedVisonic, VisonicErr> {
+        let max_age = Duration::from_secs(self.intervals.session);
+        match &self.session {
+            Some((session, since)) if since.elapsed() < max_age => Ok(session.clone()),
+            _ => {
+                let session = self.visonic.login().await?;
+                self.session = Some((session.clone(), Instant::now()));
+                Ok(session)
+            }
+        }
+    }
+
+    async fn poll_status(&mut self) -> Result<(), VisonicErr> {
+        let status = self.session().await?.status().await?;
+        self.status_changed(status);
+        Ok(())
+    }
+
+    fn status_changed(&mut self, status: ResStatus) {
+        if self.connected != Some(status.connected) {
+            self.connected = Some(status.connected);
+            self.pending.push_back(Ok(PanelChange::Connectivity {
+                connected: status.connected,
+            }));
+        }
+
+        for p in status.partitions {
+            let previous = self.partitions.insert(p.id, (p.state.clone(), p.ready));
+            let (from, was_ready) = match previous {
+                Some((state, ready)) => (Some(state), Some(ready)),
+                None => (None, None),
+            };
+            if from.as_ref() != Some(&p.state) {
+                self.pending.push_back(Ok(PanelChange::State {
+                    partition: p.id,
+                    from,
+                    to: p.state,
+                }));
+            }
+            if was_ready != Some(p.ready) {
+                self.pending.push_back(Ok(PanelChange::Ready {
+                    partition: p.id,
+                    ready: p.ready,
+                }));
+            }
+        }
+    }
+
+    async fn poll_events(&mut self) -> Result<(), VisonicErr> {
+        let events = self.session().await?.events().await?;
+        self.events_changed(events);
+        Ok(())
+    }
+
+    // the first poll only remembers the latest event, history is not replayed
+    fn events_changed(&mut self, mut events: Vec<Event>) {
+        events.sort_by_key(|e| e.event);
+
+        let latest = events.last().map(|e| e.event);
+        if let Some(last_event) = self.last_event {
+            events
+                .into_iter()
+                .filter(|e| e.event > last_event)
+                .for_each(|e| self.pending.push_back(Ok(PanelChange::Event(e))));
+        }
+        self.last_event = latest.max(self.last_event);
+    }
+
+    async fn poll_troubles(&mut self) -> Result<(), VisonicErr> {
+        let troubles = parse_list(&self.session().await?.troubles().await?)?;
+        self.troubles_changed(troubles);
+        Ok(())
+    }
+
+    // as with events, troubles present at the first poll are not reported
+    fn troubles_changed(&mut self, troubles: Vec<Value>) {
+        if let Some(previous) = &self.troubles {
+            for t in troubles.iter().filter(|t| !previous.contains(t)) {
+                self.pending
+                    .push_back(Ok(PanelChange::TroubleRaised(t.clone())));
+            }
+            for t in previous.iter().filter(|t| !troubles.contains(t)) {
+                self.pending
+                    .push_back(Ok(PanelChange::TroubleCleared(t.clone())));
+            }
+        }
+        self.troubles = Some(troubles);
+    }
+
+    /// Waits for the next due poll and runs it, a failed poll drops the session.
+    async fn poll(&mut self) {
+        let next = self
+            .next_status
+            .min(self.next_events)
+            .min(self.next_troubles);
+        tokio::time::sleep_until(next).await;
+
+        let now = Instant::now();
+        let result = if self.next_status <= now {
+            self.next_status = now + Duration::from_secs(self.intervals.status);
+            self.poll_status().await
+        } else if self.next_events <= now {
+            self.next_events = now + Duration::from_secs(self.intervals.events);
+            self.poll_events().await
+        } else {
+            self.next_troubles = now + Duration::from_secs(self.intervals.troubles);
+            self.poll_troubles().await
+        };
+
+        if let Err(err) = result {
+            self.session = None;
+            self.pending.push_back(Err(err));
+        }
+    }
+}
+
+impl Visonic {
+    /// Polls the panel and yields its changes, logging in again as sessions age or fail.
+    /// Failed polls are yielded as errors and retried at the next interval, the stream only ends
+    /// when dropped.
+    pub fn watch(
+        &self,
+        intervals: WatchIntervals,
+    ) -> impl Stream<Item = Result<PanelChange, VisonicErr>> {
+        let watcher = Watcher::new(self.clone(), intervals);
+        stream::unfold(watcher, |mut watcher| async move {
+            loop {
+                if let Some(change) = watcher.pending.pop_front() {
+                    return Some((change, watcher));
+                }
+                watcher.poll().await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::visonic::visonic::{Partition, PartitionStatus};
+
+    fn watcher() -> Watcher {
+        let visonic = Visonic::new("localhost", "1234", "app", -1, "me", "secret", "123123");
+        Watcher::new(visonic, WatchIntervals::default())
+    }
+
+    fn changes(watcher: &mut Watcher) -> Vec<PanelChange> {
+        watcher.pending.drain(..).map(|c| c.unwrap()).collect()
+    }
+
+    fn status(state: State, ready: bool) -> ResStatus {
+        ResStatus {
+            connected: true,
+            partitions: vec![Partition {
+                id: 1,
+                state,
+                status: PartitionStatus::NONE,
+                ready,
+            }],
+        }
+    }
+
+    fn event(id: u64) -> Event {
+        Event {
+            event: id,
+            type_id: None,
+            label: None,
+            description: None,
+            appointment: None,
+            datetime: None,
+            device_type: None,
+            zone: None,
+            partitions: vec![],
+        }
+    }
+
+    #[test]
+    fn reports_state_readiness_and_connectivity_changes() {
+        let mut watcher = watcher();
+        watcher.status_changed(status(State::DISARM, true));
+        assert!(matches!(
+            changes(&mut watcher)[..],
+            [
+                PanelChange::Connectivity { connected: true },
+                PanelChange::State {
+                    partition: 1,
+                    from: None,
+                    to: State::DISARM
+                },
+                PanelChange::Ready {
+                    partition: 1,
+                    ready: true
+                }
+            ]
+        ));
+
+        watcher.status_changed(status(State::DISARM, true));
+        assert!(changes(&mut watcher).is_empty());
+
+        watcher.status_changed(status(State::AWAY, false));
+        assert!(matches!(
+            changes(&mut watcher)[..],
+            [
+                PanelChange::State {
+                    partition: 1,
+                    from: Some(State::DISARM),
+                    to: State::AWAY
+                },
+                PanelChange::Ready {
+                    partition: 1,
+                    ready: false
+                }
+            ]
+        ));
+    }
+
+    #[test]
+    fn reports_only_events_after_the_first_poll() {
+        let mut watcher = watcher();
+        watcher.events_changed(vec![event(2), event(1)]);
+        assert!(changes(&mut watcher).is_empty());
+
+        watcher.events_changed(vec![event(3), event(2), event(4)]);
+        let ids: Vec<u64> = changes(&mut watcher)
+            .into_iter()
+            .map(|c| match c {
+                PanelChange::Event(e) => e.event,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(ids, vec![3, 4]);
+
+        // events that dropped out of the log are not a reason to report older ones again
+        watcher.events_changed(vec![event(1)]);
+        assert!(changes(&mut watcher).is_empty());
+    }
+
+    #[test]
+    fn reports_raised_and_cleared_troubles() {
+        let (low, tamper) = (json!({"zone": 1}), json!({"zone": 2}));
+        let mut watcher = watcher();
+        watcher.troubles_changed(vec![low.clone()]);
+        assert!(changes(&mut watcher).is_empty());
+
+        watcher.troubles_changed(vec![tamper.clone()]);
+        let changes = changes(&mut watcher);
+        assert!(matches!(&changes[..], [
+            PanelChange::TroubleRaised(raised),
+            PanelChange::TroubleCleared(cleared)
+        ] if raised == &tamper && cleared == &low));
+    }
+
+    #[test]
+    fn fails_on_trouble_bodies_that_are_no_list() {
+        assert!(parse_list("<html>").is_err());
+        assert!(parse_list(r#"{"error": 10001, "error_message": "Session"}"#).is_err());
+        assert!(parse_list("null").unwrap().is_empty());
+        assert_eq!(parse_list(r#"[{"zone": 1}]"#).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod webhook_handler;
+pub mod webhook_watcher;
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::error;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::backend::panel_backend::PanelBackend;
+use crate::webhooks::webhook_handler::{Notification, NotificationKind, Notifier, WebhookTarget};
+use visonic::{State, VisonicErr};
+
+#[derive(Clone, Deserialize)]
+pub struct WebhooksConfig {
+    pub interval: u64,
+    #[serde(default)]
+    pub retries: u8,
+    pub targets: Vec<WebhookTarget>,
+}
+
+struct Snapshot {
+    alarms: Vec<Value>,
+    alerts: Vec<Value>,
+    troubles: Vec<Value>,
+    states: HashMap<u16, State>,
+}
+
+// alarms, alerts and troubles are not modelled yet, compare them as plain JSON. A body that
+// is not a list fails the snapshot, an empty list would clear everything active.
+fn parse_list(s: String) -> Result<Vec<Value>, VisonicErr> {
+    match serde_json::from_str::<Value>(&s) {
+        Ok(Value::Array(items)) => Ok(items),
+        Ok(Value::Null) => Ok(vec![]),
+        Ok(error) if error.get("error").is_some() => {
+            Err(VisonicErr::Backend(format!("error response {}", error)))
+        }
+        Ok(other) => Ok(vec![other]),
+        Err(err) => Err(VisonicErr::Backend(format!(
+            "malformed response {}: {}",
+            s, err
+        ))),
+    }
+}
+
+async fn snapshot(backend: &dyn PanelBackend) -> Result<Snapshot, VisonicErr> {
+    let states = backend
+        .status()
+        .await?
+        .partitions
+        .into_iter()
+        .map(|p| (p.id, p.state))
+        .collect();
+
+    Ok(Snapshot {
+        alarms: parse_list(backend.alarms().await?)?,
+        alerts: parse_list(backend.alerts().await?)?,
+        troubles: parse_list(backend.troubles().await?)?,
+        states,
+    })
+}
+
+fn added<'a>(before: &'a [Value], after: &'a [Value]) -> impl Iterator<Item = &'a Value> {
+    after.iter().filter(move |v| !before.contains(v))
+}
+
+fn diff(panel_id: &str, before: &Snapshot, after: &Snapshot) -> Vec<Notification> {
+    let notification = |kind, detail: &Value| Notification {
+        kind,
+        panel_id: panel_id.to_string(),
+        detail: detail.clone(),
+    };
+
+    let mut notifications: Vec<Notification> = vec![];
+    notifications.extend(
+        added(&before.alarms, &after.alarms).map(|v| notification(NotificationKind::Alarm, v)),
+    );
+    notifications.extend(
+        added(&before.alerts, &after.alerts).map(|v| notification(NotificationKind::Alert, v)),
+    );
+    notifications.extend(
+        added(&before.troubles, &after.troubles)
+            .map(|v| notification(NotificationKind::TroubleRaised, v)),
+    );
+    notifications.extend(
+        added(&after.troubles, &before.troubles)
+            .map(|v| notification(NotificationKind::TroubleCleared, v)),
+    );
+
+    for (id, state) in after.states.iter() {
+        match before.states.get(id) {
+            Some(previous) if previous.ne(state) => notifications.push(notification(
+                NotificationKind::Arming,
+                &json!({ "partition": id, "from": previous, "to": state }),
+            )),
+            _ => (),
+        }
+    }
+
+    notifications
+}
+
+impl WebhooksConfig {
+    pub async fn watch(&self, backend: Arc<dyn PanelBackend>) {
+        let notifier = Notifier::start(&self.targets, self.retries);
+        let mut interval = tokio::time::interval(Duration::from_secs(self.interval));
+        let mut previous: Option<Snapshot> = None;
+
+        loop {
+            interval.tick().await;
+
+            let current = snapshot(backend.as_ref()).await;
+
+            match current {
+                Ok(current) => {
+                    // first snapshot is only the baseline
+                    if let Some(previous) = &previous {
+                        for n in diff(&backend.panel_id(), previous, &current).iter() {
+                            notifier.notify(n);
+                        }
+                    }
+                    previous = Some(current);
+                }
+                // the previous snapshot is kept, nothing is reported for a failed poll
+                Err(err) => error!("Failed to poll visonic for webhooks: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(troubles: Vec<Value>) -> Snapshot {
+        Snapshot {
+            alarms: vec![],
+            alerts: vec![],
+            troubles,
+            states: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn fails_on_bodies_that_are_no_list() {
+        assert!(parse_list("<html>".to_string()).is_err());
+        assert!(parse_list(r#"{"error": 10001, "error_message": "Session"}"#.to_string()).is_err());
+        assert!(parse_list("null".to_string()).unwrap().is_empty());
+        assert_eq!(parse_list(r#"[{"zone": 1}]"#.to_string()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reports_raised_and_cleared_troubles() {
+        let (low, tamper) = (json!({"zone": 1}), json!({"zone": 2}));
+        let before = snapshot(vec![low.clone()]);
+        let after = snapshot(vec![tamper.clone()]);
+
+        let notifications = diff("123123", &before, &after);
+        let kinds: Vec<(NotificationKind, &Value)> =
+            notifications.iter().map(|n| (n.kind, &n.detail)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (NotificationKind::TroubleRaised, &tamper),
+                (NotificationKind::TroubleCleared, &low)
+            ]
+        );
+        assert!(diff("123123", &after, &after).is_empty());
+    }
+}